@@ -0,0 +1,277 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::entry::{CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+use crate::storage::CacheStorage;
+
+mod har;
+mod warc;
+
+// Archives hold HTTP exchanges, so only entries with a status and a URL are
+// written. The cache key travels along (`_cacheKey` in HAR, `X-Cache-Key` in
+// WARC); records without one, e.g. captured by other tools, are keyed by the
+// importer's key function or skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Har,
+    Warc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedEntry {
+    pub key: Option<String>,
+    pub entry: CacheEntry,
+}
+
+impl ArchiveFormat {
+    pub fn encode(&self, entries: &[ArchivedEntry]) -> Vec<u8> {
+        let mut bytes = self.head();
+        let mut first = true;
+        for archived in entries.iter().filter(|archived| exportable(archived)) {
+            bytes.extend_from_slice(&self.record(archived, first));
+            first = false;
+        }
+        bytes.extend_from_slice(&self.tail());
+        bytes
+    }
+
+    fn head(&self) -> Vec<u8> {
+        match self {
+            ArchiveFormat::Har => har::head(),
+            ArchiveFormat::Warc => warc::head(),
+        }
+    }
+
+    fn record(&self, archived: &ArchivedEntry, first: bool) -> Vec<u8> {
+        match self {
+            ArchiveFormat::Har => har::record(archived, first),
+            ArchiveFormat::Warc => warc::record(archived),
+        }
+    }
+
+    fn tail(&self) -> Vec<u8> {
+        match self {
+            ArchiveFormat::Har => har::tail(),
+            ArchiveFormat::Warc => Vec::new(),
+        }
+    }
+
+    // WARC files may also be gzip compressed, as `.warc.gz` captures usually are
+    pub fn decode(&self, bytes: &[u8]) -> Result<Vec<ArchivedEntry>, CacheError> {
+        match self {
+            ArchiveFormat::Har => har::decode(bytes),
+            ArchiveFormat::Warc => warc::decode(bytes),
+        }
+    }
+}
+
+pub async fn read(path: &str, format: ArchiveFormat) -> Result<Vec<ArchivedEntry>, CacheError> {
+    format.decode(&tokio::fs::read(path).await?)
+}
+
+// Writes an archive entry by entry, so only one body is in memory at a time
+pub struct ArchiveWriter {
+    file: BufWriter<tokio::fs::File>,
+    format: ArchiveFormat,
+    written: usize,
+}
+
+impl ArchiveWriter {
+    pub async fn create(path: &str, format: ArchiveFormat) -> Result<Self, CacheError> {
+        let mut file = BufWriter::new(tokio::fs::File::create(path).await?);
+        file.write_all(&format.head()).await?;
+        Ok(ArchiveWriter {
+            file,
+            format,
+            written: 0,
+        })
+    }
+
+    // Returns `false` for entries the format cannot hold, see `ArchiveFormat`
+    pub async fn append(&mut self, archived: &ArchivedEntry) -> Result<bool, CacheError> {
+        if !exportable(archived) {
+            return Ok(false);
+        }
+        let record = self.format.record(archived, self.written == 0);
+        self.file.write_all(&record).await?;
+        self.written += 1;
+        Ok(true)
+    }
+
+    // Completes the archive; returns how many entries it holds
+    pub async fn finish(mut self) -> Result<usize, CacheError> {
+        self.file.write_all(&self.format.tail()).await?;
+        self.file.flush().await?;
+        Ok(self.written)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    // Records without a cache key that the key function could not key either
+    pub skipped: usize,
+}
+
+// Key of an archived record: the one it carries, else whatever `derive_key`
+// makes of its metadata (method and URL, usually)
+pub(crate) fn archived_key<F>(archived: &ArchivedEntry, derive_key: &F) -> Option<String>
+where
+    F: Fn(&EntryMetadata) -> Option<String>,
+{
+    archived
+        .key
+        .clone()
+        .or_else(|| derive_key(&archived.entry.metadata))
+}
+
+pub async fn write(
+    path: &str,
+    format: ArchiveFormat,
+    entries: &[ArchivedEntry],
+) -> Result<(), CacheError> {
+    tokio::fs::write(path, format.encode(entries)).await?;
+    Ok(())
+}
+
+// Preloads `storage` from an archive, e.g. before handing it to
+// `Cache::with_storage`. Imported entries are fresh for `ttl_seconds`.
+pub async fn import<S, F>(
+    storage: &S,
+    path: &str,
+    format: ArchiveFormat,
+    ttl_seconds: u64,
+    derive_key: F,
+) -> Result<ImportReport, CacheError>
+where
+    S: CacheStorage,
+    F: Fn(&EntryMetadata) -> Option<String>,
+{
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut report = ImportReport::default();
+    for archived in read(path, format).await? {
+        let Some(key) = archived_key(&archived, &derive_key) else {
+            report.skipped += 1;
+            continue;
+        };
+        let mut entry = archived.entry;
+        entry.metadata.stored_at = now;
+        entry.metadata.expires_at = now + ttl_seconds;
+        storage.put(&key, &entry).await?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+pub(crate) fn exportable(archived: &ArchivedEntry) -> bool {
+    archived.entry.metadata.status.is_some() && archived.entry.metadata.url.is_some()
+}
+
+// `1970-01-01T00:00:00Z` style timestamps, as both formats expect
+fn iso8601(seconds: u64) -> String {
+    let (days, rest) = (seconds / 86400, seconds % 86400);
+    //days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    pub(super) fn archived(key: Option<&str>, body: &[u8]) -> ArchivedEntry {
+        let mut entry = CacheEntry::new_http(
+            200,
+            "OK",
+            vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body.to_vec(),
+        );
+        entry.metadata.stored_at = 1445412480;
+        entry.metadata.method = Some("GET".to_string());
+        entry.metadata.url = Some("http://example.com/a?b=c".to_string());
+        entry.metadata.tags = vec!["release-1".to_string()];
+        ArchivedEntry {
+            key: key.map(str::to_string),
+            entry,
+        }
+    }
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(1445412480), "2015-10-21T07:28:00Z");
+        assert_eq!(iso8601(951825600), "2000-02-29T12:00:00Z");
+    }
+
+    #[tokio::test]
+    async fn test_import_into_storage() {
+        for format in [ArchiveFormat::Har, ArchiveFormat::Warc] {
+            let path = format!("/tmp/test_archive_import_{:?}", format);
+            let entries = [archived(Some("key"), b"body"), archived(None, b"keyless")];
+            let mut writer = ArchiveWriter::create(&path, format).await.unwrap();
+            for archived in &entries {
+                assert!(writer.append(archived).await.unwrap());
+            }
+            assert_eq!(writer.finish().await.unwrap(), 2);
+            assert_eq!(
+                read(&path, format).await.unwrap(),
+                format.decode(&format.encode(&entries)).unwrap()
+            );
+
+            let storage = InMemoryStorage::new();
+            let report = import(&storage, &path, format, 60, |_| None).await.unwrap();
+            assert_eq!(
+                report,
+                ImportReport {
+                    imported: 1,
+                    skipped: 1
+                }
+            );
+            let stored = storage.get("key").await.unwrap().unwrap();
+            assert_eq!(stored.body, b"body".to_vec());
+            assert_eq!(stored.metadata.expires_at, stored.metadata.stored_at + 60);
+
+            let derive_key = |metadata: &EntryMetadata| {
+                Some(format!(
+                    "{} {}",
+                    metadata.method.as_deref()?,
+                    metadata.url.as_deref()?
+                ))
+            };
+            let report = import(&storage, &path, format, 60, derive_key)
+                .await
+                .unwrap();
+            assert_eq!(
+                report,
+                ImportReport {
+                    imported: 2,
+                    skipped: 0
+                }
+            );
+            let derived = storage.get("GET http://example.com/a?b=c").await.unwrap();
+            assert_eq!(derived.unwrap().body, b"keyless".to_vec());
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+
+use super::{ArchivedEntry, iso8601};
+use crate::entry::{CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+
+// HAR 1.2, limited to the fields a cache entry has; custom fields are `_` prefixed.
+// Query parameters and cookies stay in the URL and headers.
+#[derive(Serialize, Deserialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Log {
+    version: String,
+    creator: Creator,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Creator {
+    name: String,
+    version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: Request,
+    response: Response,
+    #[serde(default)]
+    cache: serde_json::Value,
+    #[serde(default)]
+    timings: serde_json::Value,
+    #[serde(rename = "_cacheKey", default, skip_serializing_if = "Option::is_none")]
+    cache_key: Option<String>,
+    #[serde(rename = "_tags", default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Request {
+    method: String,
+    url: String,
+    http_version: String,
+    #[serde(default)]
+    cookies: Vec<serde_json::Value>,
+    #[serde(default)]
+    headers: Vec<Header>,
+    #[serde(default)]
+    query_string: Vec<Header>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    #[serde(default)]
+    cookies: Vec<serde_json::Value>,
+    headers: Vec<Header>,
+    content: Content,
+    #[serde(rename = "redirectURL", default)]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    #[serde(default)]
+    mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+// The document is written in pieces, `head`, one `record` per entry and
+// `tail`, so exports never hold more than one entry
+pub(super) fn head() -> Vec<u8> {
+    let creator = Creator {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let creator = serde_json::to_string(&creator).expect("HAR is always serializable");
+    format!(
+        "{{\n  \"log\": {{\n    \"version\": \"1.2\",\n    \"creator\": {},\n    \"entries\": [",
+        creator
+    )
+    .into_bytes()
+}
+
+pub(super) fn record(archived: &ArchivedEntry, first: bool) -> Vec<u8> {
+    let mut record = if first {
+        b"\n".to_vec()
+    } else {
+        b",\n".to_vec()
+    };
+    serde_json::to_writer_pretty(&mut record, &to_har_entry(archived))
+        .expect("HAR is always serializable");
+    record
+}
+
+pub(super) fn tail() -> Vec<u8> {
+    b"\n    ]\n  }\n}\n".to_vec()
+}
+
+pub(super) fn decode(bytes: &[u8]) -> Result<Vec<ArchivedEntry>, CacheError> {
+    let har: Har = serde_json::from_slice(bytes)?;
+    har.log.entries.into_iter().map(from_har_entry).collect()
+}
+
+fn to_har_entry(archived: &ArchivedEntry) -> Entry {
+    let metadata = &archived.entry.metadata;
+    let body = &archived.entry.body;
+    //Binary bodies cannot be HAR text as they are
+    let (text, encoding) = match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (BASE64.encode(body), Some("base64".to_string())),
+    };
+
+    Entry {
+        started_date_time: iso8601(metadata.stored_at),
+        time: 0.0,
+        request: Request {
+            method: metadata.method.clone().unwrap_or_else(|| "GET".to_string()),
+            url: metadata.url.clone().unwrap_or_default(),
+            http_version: "HTTP/1.1".to_string(),
+            cookies: Vec::new(),
+            headers: Vec::new(),
+            query_string: Vec::new(),
+            headers_size: -1,
+            body_size: 0,
+        },
+        response: Response {
+            status: metadata.status.unwrap_or_default(),
+            status_text: metadata.reason.clone(),
+            http_version: "HTTP/1.1".to_string(),
+            cookies: Vec::new(),
+            headers: metadata
+                .headers
+                .iter()
+                .map(|(name, value)| Header {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            content: Content {
+                size: body.len() as i64,
+                mime_type: metadata.header("Content-Type").unwrap_or("").to_string(),
+                text: Some(text),
+                encoding,
+            },
+            redirect_url: metadata.header("Location").unwrap_or("").to_string(),
+            headers_size: -1,
+            body_size: body.len() as i64,
+        },
+        cache: serde_json::json!({}),
+        timings: serde_json::json!({ "send": 0, "wait": 0, "receive": 0 }),
+        cache_key: archived.key.clone(),
+        tags: metadata.tags.clone(),
+    }
+}
+
+fn from_har_entry(entry: Entry) -> Result<ArchivedEntry, CacheError> {
+    let content = entry.response.content;
+    let body = match (content.text, content.encoding.as_deref()) {
+        (Some(text), Some("base64")) => BASE64
+            .decode(text)
+            .map_err(|e| CacheError::Serialization(format!("Invalid HAR content: {}", e)))?,
+        (Some(text), _) => text.into_bytes(),
+        (None, _) => Vec::new(),
+    };
+
+    let metadata = EntryMetadata {
+        status: Some(entry.response.status),
+        reason: entry.response.status_text,
+        headers: entry
+            .response
+            .headers
+            .into_iter()
+            .map(|header| (header.name, header.value))
+            .collect(),
+        method: Some(entry.request.method),
+        url: Some(entry.request.url),
+        tags: entry.tags,
+        ..EntryMetadata::default()
+    };
+    Ok(ArchivedEntry {
+        key: entry.cache_key,
+        entry: CacheEntry { metadata, body },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::tests::archived;
+
+    #[test]
+    fn test_har_roundtrip() {
+        let entries = vec![
+            archived(Some("text"), b"Hello World!"),
+            archived(Some("binary"), &[0xff, 0x00, 0xfe]),
+        ];
+        let har = super::super::ArchiveFormat::Har.encode(&entries);
+        let json: serde_json::Value = serde_json::from_slice(&har).unwrap();
+        assert_eq!(
+            json["log"]["entries"][0]["startedDateTime"],
+            "2015-10-21T07:28:00Z"
+        );
+        assert_eq!(
+            json["log"]["entries"][1]["response"]["content"]["encoding"],
+            "base64"
+        );
+
+        let decoded = decode(&har).unwrap();
+        for (decoded, original) in decoded.iter().zip(&entries) {
+            assert_eq!(decoded.key, original.key);
+            assert_eq!(decoded.entry.body, original.entry.body);
+            assert_eq!(
+                decoded.entry.metadata,
+                EntryMetadata {
+                    stored_at: 0,
+                    ..original.entry.metadata.clone()
+                }
+            );
+        }
+    }
+}
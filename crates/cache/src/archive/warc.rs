@@ -0,0 +1,240 @@
+use std::io::Read;
+
+use super::{ArchivedEntry, iso8601};
+use crate::entry::{CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+
+// WARC 1.1 with one `response` record per entry. Fields the format has no
+// room for are kept in `X-Cache-*` record headers.
+const VERSION: &str = "WARC/1.1";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+pub(super) fn head() -> Vec<u8> {
+    let mut warc = Vec::new();
+    let info = format!(
+        "software: {}/{}\r\nformat: WARC File Format 1.1\r\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    write_record(
+        &mut warc,
+        "warcinfo",
+        &[],
+        "application/warc-fields",
+        info.as_bytes(),
+    );
+    warc
+}
+
+pub(super) fn record(archived: &ArchivedEntry) -> Vec<u8> {
+    let metadata = &archived.entry.metadata;
+    let mut fields = vec![
+        ("WARC-Date", iso8601(metadata.stored_at)),
+        ("WARC-Target-URI", metadata.url.clone().unwrap_or_default()),
+    ];
+    if let Some(key) = &archived.key {
+        fields.push(("X-Cache-Key", key.clone()));
+    }
+    if let Some(method) = &metadata.method {
+        fields.push(("X-Cache-Method", method.clone()));
+    }
+    if !metadata.tags.is_empty() {
+        fields.push(("X-Cache-Tags", metadata.tags.join(",")));
+    }
+
+    let mut block = format!(
+        "HTTP/1.1 {} {}\r\n",
+        metadata.status.unwrap_or_default(),
+        metadata.reason
+    );
+    for (name, value) in &metadata.headers {
+        block.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    block.push_str("\r\n");
+    let mut block = block.into_bytes();
+    block.extend_from_slice(&archived.entry.body);
+
+    let mut warc = Vec::new();
+    write_record(
+        &mut warc,
+        "response",
+        &fields,
+        "application/http;msgtype=response",
+        &block,
+    );
+    warc
+}
+
+fn write_record(
+    warc: &mut Vec<u8>,
+    record_type: &str,
+    fields: &[(&str, String)],
+    content_type: &str,
+    block: &[u8],
+) {
+    let mut head = format!(
+        "{}\r\nWARC-Type: {}\r\nWARC-Record-ID: <urn:uuid:{}>\r\n",
+        VERSION,
+        record_type,
+        uuid::Uuid::new_v4()
+    );
+    for (name, value) in fields {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        content_type,
+        block.len()
+    ));
+    warc.extend_from_slice(head.as_bytes());
+    warc.extend_from_slice(block);
+    warc.extend_from_slice(b"\r\n\r\n");
+}
+
+// Only `response` records carrying an HTTP response become entries
+pub(super) fn decode(bytes: &[u8]) -> Result<Vec<ArchivedEntry>, CacheError> {
+    if bytes.starts_with(GZIP_MAGIC) {
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(bytes).read_to_end(&mut decoded)?;
+        return decode(&decoded);
+    }
+
+    let invalid = |reason: &str| CacheError::Serialization(format!("Invalid WARC: {}", reason));
+    let mut entries = Vec::new();
+    let mut rest = bytes;
+    loop {
+        rest = trim_line_breaks(rest);
+        if rest.is_empty() {
+            return Ok(entries);
+        }
+
+        let (fields, after_head) = split_head(rest).ok_or_else(|| invalid("truncated record"))?;
+        let mut lines = fields.lines();
+        if !lines.next().is_some_and(|line| line.starts_with("WARC/")) {
+            return Err(invalid("missing version line"));
+        }
+        let fields: Vec<(&str, &str)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| *v)
+        };
+
+        let length: usize = field("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| invalid("missing Content-Length"))?;
+        let block = after_head
+            .get(..length)
+            .ok_or_else(|| invalid("truncated block"))?;
+        rest = &after_head[length..];
+
+        let is_http_response = field("WARC-Type") == Some("response")
+            && field("Content-Type").is_some_and(|t| t.starts_with("application/http"));
+        if !is_http_response {
+            continue;
+        }
+        let Some(mut entry) = parse_response(block) else {
+            return Err(invalid("unparseable HTTP response"));
+        };
+        entry.metadata.url = field("WARC-Target-URI").map(str::to_string);
+        entry.metadata.method = Some(field("X-Cache-Method").unwrap_or("GET").to_string());
+        entry.metadata.tags = field("X-Cache-Tags")
+            .map(|tags| tags.split(',').map(str::to_string).collect())
+            .unwrap_or_default();
+        entries.push(ArchivedEntry {
+            key: field("X-Cache-Key").map(str::to_string),
+            entry,
+        });
+    }
+}
+
+fn trim_line_breaks(mut bytes: &[u8]) -> &[u8] {
+    while let Some(rest) = bytes
+        .strip_prefix(b"\r\n".as_slice())
+        .or_else(|| bytes.strip_prefix(b"\n".as_slice()))
+    {
+        bytes = rest;
+    }
+    bytes
+}
+
+// Head as text plus everything after its terminating blank line
+fn split_head(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let end = bytes.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&bytes[..end]).ok()?;
+    Some((head, &bytes[end + 4..]))
+}
+
+fn parse_response(block: &[u8]) -> Option<CacheEntry> {
+    let (head, body) = split_head(block)?;
+    let mut lines = head.lines();
+    let mut status_line = lines.next()?.splitn(3, ' ');
+    status_line.next()?; //version
+    let status = status_line.next()?.parse().ok()?;
+    let reason = status_line.next().unwrap_or("").to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Some(CacheEntry {
+        metadata: EntryMetadata {
+            status: Some(status),
+            reason,
+            headers,
+            ..EntryMetadata::default()
+        },
+        body: body.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::archive::tests::archived;
+
+    #[test]
+    fn test_warc_roundtrip() {
+        let entries = vec![
+            archived(Some("text"), b"Hello World!"),
+            archived(Some("binary"), &[0xff, 0x00, b'\r', b'\n', b'\r', b'\n']),
+        ];
+        let warc = super::super::ArchiveFormat::Warc.encode(&entries);
+        assert!(warc.starts_with(b"WARC/1.1\r\nWARC-Type: warcinfo\r\n"));
+
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzipped.write_all(&warc).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+
+        for bytes in [warc, gzipped] {
+            let decoded = decode(&bytes).unwrap();
+            assert_eq!(decoded.len(), 2);
+            for (decoded, original) in decoded.iter().zip(&entries) {
+                assert_eq!(decoded.key, original.key);
+                assert_eq!(
+                    decoded.entry,
+                    CacheEntry {
+                        metadata: EntryMetadata {
+                            stored_at: 0,
+                            ..original.entry.metadata.clone()
+                        },
+                        body: original.entry.body.clone(),
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_warc_rejects_truncated_records() {
+        let warc = super::super::ArchiveFormat::Warc.encode(&[archived(Some("key"), b"body")]);
+        assert!(decode(&warc[..warc.len() - 10]).is_err());
+        assert!(decode(b"not a warc\r\n\r\n").is_err());
+    }
+}
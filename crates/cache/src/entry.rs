@@ -0,0 +1,245 @@
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+// Serialized layout: MAGIC | metadata length (u32, big endian) | metadata JSON | body
+// | CRC32 of metadata JSON and body (u32, big endian)
+const MAGIC: &[u8; 5] = b"RLCE2";
+pub(crate) const CHECKSUM_LEN: usize = 4;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    pub stored_at: u64,
+    pub expires_at: u64,
+    // None for payloads that are not a parsed HTTP response, e.g. CONNECT tunnels
+    pub status: Option<u16>,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub hit_count: u64,
+    // How the stored body is compressed, see `storage::CompressedStorage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    // Body length before compression, for `CacheStorage::body_size` after a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncompressed_len: Option<u64>,
+    // Set on entries sealed by `storage::EncryptedStorage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    // Method, URL and tags of a sealed entry, encrypted on their own so the
+    // index can be rebuilt without opening bodies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed_index: Option<String>,
+    // SHA-256 of a body stored once by `storage::DedupStorage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    // Request the entry answers, recorded for invalidation by URL, prefix or host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    // Request headers the key was made of and the request body, kept for
+    // recordings so they show what each response answers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request_headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<Vec<u8>>,
+    // Attached at put time, see `Cache::invalidate_tag`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Set on entries standing in for an upstream that could not be reached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<UpstreamFailure>,
+    // Full key of entries whose file name had to be hashed, see `SimpleFileStorage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encryption {
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamFailure {
+    Dns,
+    ConnectionRefused,
+}
+
+impl EntryMetadata {
+    // Failed outcomes, kept only for `Cache::set_negative_ttl` when enabled
+    pub fn is_negative(&self) -> bool {
+        self.failure.is_some() || matches!(self.status, Some(404 | 410))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("metadata is always serializable")
+    }
+}
+
+// Everything in front of the body; the checksum covers `metadata_json` and the body
+pub(crate) fn encode_header(metadata_json: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(header_len(metadata_json));
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&(metadata_json.len() as u32).to_be_bytes());
+    header.extend_from_slice(metadata_json);
+    header
+}
+
+pub(crate) fn header_len(metadata_json: &[u8]) -> usize {
+    MAGIC.len() + 4 + metadata_json.len()
+}
+
+// Parsed metadata plus its raw JSON, which the checksum is computed over
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Option<(EntryMetadata, Vec<u8>)> {
+    let mut prefix = [0u8; MAGIC.len() + 4];
+    reader.read_exact(&mut prefix).ok()?;
+    let (magic, len) = prefix.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return None;
+    }
+
+    let len = u32::from_be_bytes(len.try_into().ok()?) as u64;
+    let mut metadata_json = Vec::new();
+    reader.take(len).read_to_end(&mut metadata_json).ok()?;
+    if metadata_json.len() as u64 != len {
+        return None;
+    }
+    Some((serde_json::from_slice(&metadata_json).ok()?, metadata_json))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheEntry {
+    pub metadata: EntryMetadata,
+    pub body: Vec<u8>,
+}
+
+impl CacheEntry {
+    pub fn new(body: Vec<u8>) -> Self {
+        CacheEntry {
+            metadata: EntryMetadata::default(),
+            body,
+        }
+    }
+
+    pub fn new_http(
+        status: u16,
+        reason: &str,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Self {
+        CacheEntry {
+            metadata: EntryMetadata {
+                status: Some(status),
+                reason: reason.to_string(),
+                headers,
+                ..EntryMetadata::default()
+            },
+            body,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.metadata.header(name)
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let metadata_json = self.metadata.to_json();
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&metadata_json);
+        hasher.update(&self.body);
+
+        let mut bytes = encode_header(&metadata_json);
+        bytes.reserve(self.body.len() + CHECKSUM_LEN);
+        bytes.extend_from_slice(&self.body);
+        bytes.extend_from_slice(&hasher.finalize().to_be_bytes());
+        bytes
+    }
+
+    // Reads only the metadata of a serialized entry, leaving the body unread.
+    // The checksum is not verified here, only by `from_bytes`.
+    pub fn read_metadata<R: Read>(reader: &mut R) -> Option<EntryMetadata> {
+        read_header(reader).map(|(metadata, _)| metadata)
+    }
+
+    // None if the bytes are not a serialized entry or fail the checksum
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let rest = bytes.strip_prefix(MAGIC.as_slice())?;
+        let (len, rest) = rest.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        let (rest, checksum) = rest.split_last_chunk::<CHECKSUM_LEN>()?;
+        if rest.len() < len || crc32fast::hash(rest) != u32::from_be_bytes(*checksum) {
+            return None;
+        }
+
+        let (metadata, body) = rest.split_at(len);
+        Some(CacheEntry {
+            metadata: serde_json::from_slice(metadata).ok()?,
+            body: body.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let mut entry = CacheEntry::new_http(
+            200,
+            "OK",
+            vec![("Content-Type".to_string(), "text/plain".to_string())],
+            b"Hello World!".to_vec(),
+        );
+        entry.metadata.stored_at = 10;
+        entry.metadata.expires_at = 70;
+
+        let decoded = CacheEntry::from_bytes(&entry.to_bytes()).unwrap();
+        assert_eq!(decoded, entry);
+        assert_eq!(decoded.content_type(), Some("text/plain"));
+
+        let metadata = CacheEntry::read_metadata(&mut entry.to_bytes().as_slice()).unwrap();
+        assert_eq!(metadata, entry.metadata);
+    }
+
+    #[test]
+    fn test_entry_rejects_garbage() {
+        assert_eq!(CacheEntry::from_bytes(b"Hello World!"), None);
+
+        let bytes = CacheEntry::new(b"body".to_vec()).to_bytes();
+        assert_eq!(CacheEntry::from_bytes(&bytes[..8]), None);
+        assert_eq!(CacheEntry::read_metadata(&mut &bytes[..12]), None);
+    }
+
+    #[test]
+    fn test_entry_rejects_corruption() {
+        let mut bytes = CacheEntry::new(b"body".to_vec()).to_bytes();
+        let body_start = bytes.len() - CHECKSUM_LEN - 4;
+        bytes[body_start] ^= 0xff;
+        assert_eq!(CacheEntry::from_bytes(&bytes), None);
+
+        let bytes = CacheEntry::new(b"body".to_vec()).to_bytes();
+        assert_eq!(CacheEntry::from_bytes(&bytes[..bytes.len() - 1]), None);
+    }
+}
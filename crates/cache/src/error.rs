@@ -0,0 +1,89 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    // Metadata could not be encoded or decoded
+    Serialization(String),
+    // The backing store ran out of space
+    Capacity(String),
+    // A stored entry failed its integrity check; it has been dropped already
+    Corrupted(String),
+    InvalidKey(String),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "Cache I/O error: {}", e),
+            CacheError::Serialization(e) => write!(f, "Cache serialization error: {}", e),
+            CacheError::Capacity(e) => write!(f, "Cache storage full: {}", e),
+            CacheError::Corrupted(key) => write!(f, "Corrupted cache entry for key: {}", key),
+            CacheError::InvalidKey(key) => write!(f, "Invalid cache key: {:?}", key),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CacheError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                CacheError::Capacity(e.to_string())
+            }
+            _ => CacheError::Io(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for CacheError {
+    fn from(e: serde_json::Error) -> Self {
+        CacheError::Serialization(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for CacheError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::DiskFull) => CacheError::Capacity(e.to_string()),
+            _ => CacheError::Io(std::io::Error::other(e)),
+        }
+    }
+}
+
+// Entry writers are `AsyncWrite`s and can only report `io::Error`s
+impl From<CacheError> for std::io::Error {
+    fn from(e: CacheError) -> Self {
+        match e {
+            CacheError::Io(e) => e,
+            CacheError::Capacity(_) => std::io::Error::new(std::io::ErrorKind::StorageFull, e),
+            CacheError::InvalidKey(_) => std::io::Error::new(std::io::ErrorKind::InvalidInput, e),
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_conversion() {
+        let full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        assert!(matches!(CacheError::from(full), CacheError::Capacity(_)));
+
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert!(matches!(CacheError::from(missing), CacheError::Io(_)));
+
+        let err = std::io::Error::from(CacheError::InvalidKey(String::new()));
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,43 +1,180 @@
+use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll, ready};
 use std::time::SystemTime;
 
 use dashmap::DashMap;
+use tokio::io::AsyncWrite;
 
+pub mod archive;
+pub mod entry;
+mod error;
+mod stats;
 pub mod storage;
-use storage::{CacheStorage, InMemoryStorage, SimpleFileStorage};
+use archive::{ArchiveFormat, ArchiveWriter, ArchivedEntry, ImportReport};
+pub use entry::{CacheEntry, Compression, Encryption, EntryMetadata, UpstreamFailure};
+pub use error::CacheError;
+use stats::Stats;
+pub use stats::{CacheStats, StatsSnapshot};
+use storage::{
+    CacheStorage, EntryReader, EntryWriter, InMemoryStorage, SimpleFileStorage, SqliteStorage,
+    TieredStorage,
+};
+
+// Describe the `304` itself rather than the stored body
+const SKIPPED_ON_REFRESH: [&str; 4] = [
+    "Content-Length",
+    "Content-Range",
+    "Transfer-Encoding",
+    "Connection",
+];
+
+// Outcome of a freshness-aware lookup. Stale entries are kept around so callers
+// can revalidate them upstream instead of refetching the whole body. Bodies are
+// read separately through `Cache::open_read`.
+#[derive(Debug, PartialEq)]
+pub enum CacheLookup {
+    Fresh(EntryMetadata),
+    Stale {
+        metadata: EntryMetadata,
+        stale_seconds: u64,
+    },
+    Miss,
+}
 
 #[derive(Debug)]
 pub struct Cache<T: CacheStorage> {
     size: AtomicUsize,
     ttl_seconds: AtomicU64,
+    stale_while_revalidate_seconds: AtomicU64,
+    stale_if_error_seconds: AtomicU64,
+    negative_ttl_seconds: AtomicU64,
     key_and_evict_map: DashMap<String, u64>,
+    key_and_hits_map: DashMap<String, u64>,
+    key_and_entry_map: DashMap<String, IndexedEntry>,
+    stats: Stats,
     store: T,
 }
 
+// What invalidation and stats need per entry, kept in memory so purges and
+// per-host counters do not read every entry
+#[derive(Debug, Default)]
+struct IndexedEntry {
+    method: Option<String>,
+    url: Option<String>,
+    tags: Vec<String>,
+    host: Option<String>,
+    bytes: u64,
+}
+
+impl IndexedEntry {
+    fn new(metadata: &EntryMetadata, bytes: u64) -> Self {
+        IndexedEntry {
+            method: metadata.method.clone(),
+            url: metadata.url.clone(),
+            tags: metadata.tags.clone(),
+            host: metadata.url.as_deref().and_then(url_host),
+            bytes,
+        }
+    }
+}
+
+// Key of the URL's counters in `StatsSnapshot::hosts`
+fn url_host(url: &str) -> Option<String> {
+    url_authority(url).map(str::to_ascii_lowercase)
+}
+
+// Authority of an absolute URL, e.g. `example.com:8080` for `http://example.com:8080/a`
+fn url_authority(url: &str) -> Option<&str> {
+    let rest = &url[url.find("://")? + 3..];
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    Some(
+        authority
+            .rsplit_once('@')
+            .map_or(authority, |(_, host)| host),
+    )
+}
+
+// `host` matches with or without the port
+fn url_has_host(url: &str, host: &str) -> bool {
+    url_authority(url).is_some_and(|authority| {
+        authority.eq_ignore_ascii_case(host)
+            || authority
+                .rsplit_once(':')
+                .is_some_and(|(name, _)| name.eq_ignore_ascii_case(host))
+    })
+}
+
 impl Cache<InMemoryStorage> {
     pub fn new(size: &usize, ttl_seconds: &u64) -> Cache<InMemoryStorage> {
-        Cache {
-            size: (*size).into(),
-            ttl_seconds: (*ttl_seconds).into(),
-            key_and_evict_map: DashMap::new(),
-            store: InMemoryStorage::new(),
-        }
+        Cache::with_storage(size, ttl_seconds, InMemoryStorage::new())
     }
 }
 
 impl Cache<SimpleFileStorage> {
+    // Picks up entries left in `path` by a previous run
     pub fn new_file_cache(size: &usize, ttl_seconds: &u64, path: &str) -> Cache<SimpleFileStorage> {
-        Cache {
+        Cache::with_storage(size, ttl_seconds, SimpleFileStorage::new(path))
+    }
+}
+
+impl Cache<SqliteStorage> {
+    // Single database file at `path`, created if missing; picks up previous entries
+    pub fn new_sqlite_cache(
+        size: &usize,
+        ttl_seconds: &u64,
+        path: &str,
+    ) -> Result<Cache<SqliteStorage>, CacheError> {
+        Ok(Cache::with_storage(
+            size,
+            ttl_seconds,
+            SqliteStorage::new(path)?,
+        ))
+    }
+}
+
+impl Cache<TieredStorage> {
+    // Hot entries in memory, the rest under `path`; each tier has its own byte limit
+    pub fn new_tiered_cache(
+        size: &usize,
+        ttl_seconds: &u64,
+        path: &str,
+        memory_limit_bytes: &u64,
+        disk_limit_bytes: &u64,
+    ) -> Cache<TieredStorage> {
+        let store = TieredStorage::new(path, *memory_limit_bytes, *disk_limit_bytes);
+        Cache::with_storage(size, ttl_seconds, store)
+    }
+}
+
+impl<T: CacheStorage> Cache<T> {
+    pub fn with_storage(size: &usize, ttl_seconds: &u64, store: T) -> Cache<T> {
+        let cache = Cache {
             size: (*size).into(),
             ttl_seconds: (*ttl_seconds).into(),
+            stale_while_revalidate_seconds: 0.into(),
+            stale_if_error_seconds: 0.into(),
+            negative_ttl_seconds: 0.into(),
             key_and_evict_map: DashMap::new(),
-            store: SimpleFileStorage::new(path),
+            key_and_hits_map: DashMap::new(),
+            key_and_entry_map: DashMap::new(),
+            stats: Stats::default(),
+            store,
+        };
+        for (key, metadata) in cache.store.load_index() {
+            let bytes = cache.store.body_size(&key).unwrap_or(0);
+            cache.index(
+                &key,
+                metadata.expires_at,
+                IndexedEntry::new(&metadata, bytes),
+            );
+            cache.key_and_hits_map.insert(key, metadata.hit_count);
         }
+        cache
     }
-}
 
-impl<T: CacheStorage> Cache<T> {
     pub fn get_size(&self) -> usize {
         self.size.load(Ordering::Relaxed)
     }
@@ -53,6 +190,18 @@ impl<T: CacheStorage> Cache<T> {
         self.ttl_seconds.load(Ordering::Relaxed)
     }
 
+    pub fn get_stale_while_revalidate(&self) -> u64 {
+        self.stale_while_revalidate_seconds.load(Ordering::Relaxed)
+    }
+
+    pub fn get_stale_if_error(&self) -> u64 {
+        self.stale_if_error_seconds.load(Ordering::Relaxed)
+    }
+
+    pub fn get_negative_ttl(&self) -> u64 {
+        self.negative_ttl_seconds.load(Ordering::Relaxed)
+    }
+
     pub async fn set_size(&self, size: &usize) {
         self.size.store(*size, Ordering::Relaxed);
     }
@@ -61,32 +210,445 @@ impl<T: CacheStorage> Cache<T> {
         self.ttl_seconds.store(*ttl_seconds, Ordering::Relaxed);
     }
 
-    pub async fn put(&self, key: &str, value: &[u8]) -> Result<(), ()> {
+    // Grace window after expiry in which a stale entry is served while refreshing it
+    pub async fn set_stale_while_revalidate(&self, seconds: &u64) {
+        self.stale_while_revalidate_seconds
+            .store(*seconds, Ordering::Relaxed);
+    }
+
+    // Grace window after expiry in which a stale entry is served if the upstream fails
+    pub async fn set_stale_if_error(&self, seconds: &u64) {
+        self.stale_if_error_seconds
+            .store(*seconds, Ordering::Relaxed);
+    }
+
+    // TTL of failed outcomes such as 404s or refused connections, so repeated
+    // failing requests are answered from the cache. 0, the default, disables it:
+    // 404s and 410s then keep the regular TTL and failures are not cached.
+    pub async fn set_negative_ttl(&self, seconds: &u64) {
+        self.negative_ttl_seconds.store(*seconds, Ordering::Relaxed);
+    }
+
+    pub fn caches_failures(&self) -> bool {
+        self.get_negative_ttl() > 0
+    }
+
+    pub fn within_stale_while_revalidate(&self, stale_seconds: u64) -> bool {
+        stale_seconds < self.get_stale_while_revalidate()
+    }
+
+    pub fn within_stale_if_error(&self, stale_seconds: u64) -> bool {
+        stale_seconds < self.get_stale_if_error()
+    }
+
+    // Stamps the entry with its storage and expiry time before storing it
+    pub async fn put(&self, key: &str, mut entry: CacheEntry) -> Result<(), CacheError> {
+        let evict_time = self.stamp(&mut entry.metadata);
+        self.store.put(key, &entry).await?;
+        let indexed = IndexedEntry::new(&entry.metadata, entry.body.len() as u64);
+        self.index(key, evict_time, indexed);
+        Ok(())
+    }
+
+    // Streaming counterpart of `put`: the entry becomes visible once the
+    // returned writer is shut down
+    pub async fn open_write(
+        &self,
+        key: &str,
+        mut metadata: EntryMetadata,
+    ) -> Result<CacheWriter<'_, T>, CacheError> {
+        let evict_time = self.stamp(&mut metadata);
+        let indexed = IndexedEntry::new(&metadata, 0);
+        let inner = self.store.open_write(key, metadata).await?;
+        Ok(CacheWriter {
+            cache: self,
+            key: key.to_string(),
+            evict_time,
+            indexed: Some(indexed),
+            written: 0,
+            inner,
+        })
+    }
+
+    // Body of an indexed entry regardless of its freshness, see `lookup`
+    pub async fn open_read(
+        &self,
+        key: &str,
+    ) -> Result<Option<(EntryMetadata, EntryReader)>, CacheError> {
+        if !self.key_and_evict_map.contains_key(key) {
+            return Ok(None);
+        }
+        let Some((mut metadata, reader)) =
+            self.forget_if_lost(key, self.store.open_read(key).await)?
+        else {
+            return Ok(None);
+        };
+        self.reflect_counters(key, &mut metadata);
+        Ok(Some((metadata, reader)))
+    }
+
+    fn stamp(&self, metadata: &mut EntryMetadata) -> u64 {
         let now = Self::now_seconds();
-        let evict_time = now + self.get_ttl();
+        let ttl = if metadata.is_negative() && self.caches_failures() {
+            self.get_negative_ttl()
+        } else {
+            self.get_ttl()
+        };
+        metadata.stored_at = now;
+        metadata.expires_at = now + ttl;
+        metadata.hit_count = 0;
+        metadata.expires_at
+    }
+
+    fn index(&self, key: &str, evict_time: u64, indexed: IndexedEntry) {
         self.key_and_evict_map.insert(key.to_string(), evict_time);
-        self.store.put(key, value).await
+        self.key_and_hits_map.insert(key.to_string(), 0);
+        self.stats.record(indexed.host.as_deref(), |stats| {
+            stats.entries += 1;
+            stats.bytes += indexed.bytes;
+        });
+        if let Some(replaced) = self.key_and_entry_map.insert(key.to_string(), indexed) {
+            self.uncount(&replaced);
+        }
+    }
+
+    fn unindex(&self, key: &str) -> Option<IndexedEntry> {
+        self.key_and_evict_map.remove(key);
+        self.key_and_hits_map.remove(key);
+        let (_, removed) = self.key_and_entry_map.remove(key)?;
+        self.uncount(&removed);
+        Some(removed)
+    }
+
+    fn uncount(&self, indexed: &IndexedEntry) {
+        self.stats.record(indexed.host.as_deref(), |stats| {
+            stats.entries = stats.entries.saturating_sub(1);
+            stats.bytes = stats.bytes.saturating_sub(indexed.bytes);
+        });
+    }
+
+    // Drops entries that went away without being invalidated
+    fn evict(&self, key: &str) {
+        if let Some(removed) = self.unindex(key) {
+            self.stats
+                .record(removed.host.as_deref(), |stats| stats.evictions += 1);
+        }
     }
 
-    pub async fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
+    fn host_of(&self, key: &str) -> Option<String> {
+        self.key_and_entry_map.get(key)?.host.clone()
+    }
+
+    fn count_lookup(&self, host: Option<&str>, lookup: &CacheLookup) {
+        self.stats.record(host, |stats| match lookup {
+            CacheLookup::Fresh(_) => stats.hits += 1,
+            CacheLookup::Stale { .. } => stats.expired += 1,
+            CacheLookup::Miss => stats.misses += 1,
+        });
+    }
+
+    // Counters since the cache was created, globally and per host
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    // Drops the index entry of keys the storage no longer has, including
+    // corrupted entries it has already removed
+    fn forget_if_lost<V>(
+        &self,
+        key: &str,
+        result: Result<Option<V>, CacheError>,
+    ) -> Result<Option<V>, CacheError> {
+        if matches!(result, Ok(None) | Err(CacheError::Corrupted(_))) {
+            self.evict(key);
+        }
+        result
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Arc<CacheEntry>>, CacheError> {
         let now = Self::now_seconds();
         let evict_time_opt = self.key_and_evict_map.get(key).map(|guard| *guard);
+        let host = self.host_of(key);
         if let Some(evict_time) = evict_time_opt {
             if evict_time > now {
-                return self.store.get(key).await; //found and valid
+                let found = self.forget_if_lost(key, self.store.get(key).await);
+                let Ok(Some(mut entry)) = found else {
+                    self.stats
+                        .record(host.as_deref(), |stats| stats.misses += 1);
+                    return found.map(|_| None);
+                };
+                self.hit(key); //found and valid
+                self.stats.record(host.as_deref(), |stats| stats.hits += 1);
+                self.reflect_counters(key, &mut entry.metadata);
+                return Ok(Some(Arc::new(entry)));
             } else {
-                self.store.delete(key).await.ok(); //expired
-                self.key_and_evict_map.remove(key);
-                return None; //found but expired
+                self.stats
+                    .record(host.as_deref(), |stats| stats.expired += 1);
+                self.evict(key);
+                self.store.delete(key).await?; //expired
+                return Ok(None); //found but expired
+            }
+        }
+        self.stats.record(None, |stats| stats.misses += 1);
+        Ok(None) //Key not found
+    }
+
+    fn hit(&self, key: &str) {
+        *self.key_and_hits_map.entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    // Stored metadata only knows the state at write time
+    fn reflect_counters(&self, key: &str, metadata: &mut EntryMetadata) {
+        if let Some(hits) = self.key_and_hits_map.get(key) {
+            metadata.hit_count = *hits;
+        }
+        if let Some(evict_time) = self.key_and_evict_map.get(key) {
+            metadata.expires_at = *evict_time;
+        }
+    }
+
+    pub async fn lookup(&self, key: &str) -> Result<CacheLookup, CacheError> {
+        let host = self.host_of(key);
+        self.lookup_counted(key, host.as_deref()).await
+    }
+
+    // Same as `lookup`, also counting misses on keys never stored under the
+    // URL's host
+    pub async fn lookup_for_url(&self, key: &str, url: &str) -> Result<CacheLookup, CacheError> {
+        let host = self.host_of(key).or_else(|| url_host(url));
+        self.lookup_counted(key, host.as_deref()).await
+    }
+
+    async fn lookup_counted(
+        &self,
+        key: &str,
+        host: Option<&str>,
+    ) -> Result<CacheLookup, CacheError> {
+        let lookup = self.lookup_uncounted(key).await;
+        self.count_lookup(host, lookup.as_ref().unwrap_or(&CacheLookup::Miss));
+        lookup
+    }
+
+    async fn lookup_uncounted(&self, key: &str) -> Result<CacheLookup, CacheError> {
+        let now = Self::now_seconds();
+        let evict_time_opt = self.key_and_evict_map.get(key).map(|guard| *guard);
+        let Some(evict_time) = evict_time_opt else {
+            return Ok(CacheLookup::Miss); //Key not found
+        };
+
+        let lookup = match self.forget_if_lost(key, self.store.metadata(key).await)? {
+            Some(mut metadata) if evict_time > now => {
+                self.hit(key);
+                self.reflect_counters(key, &mut metadata);
+                CacheLookup::Fresh(metadata)
+            }
+            Some(mut metadata) => {
+                self.reflect_counters(key, &mut metadata);
+                CacheLookup::Stale {
+                    metadata, //expired, but kept for revalidation
+                    stale_seconds: now - evict_time,
+                }
             }
+            None => CacheLookup::Miss, //storage lost the value
+        };
+        Ok(lookup)
+    }
+
+    // Restamps an existing entry after a `304 Not Modified`, taking over the
+    // validators and freshness headers it carried (ETag, Cache-Control, Date..)
+    pub async fn refresh(
+        &self,
+        key: &str,
+        headers: &[(String, String)],
+    ) -> Result<bool, CacheError> {
+        if !self.key_and_evict_map.contains_key(key) {
+            return Ok(false);
+        }
+        let Some(mut metadata) = self.forget_if_lost(key, self.store.metadata(key).await)? else {
+            return Ok(false);
+        };
+        let merged: Vec<&(String, String)> = headers
+            .iter()
+            .filter(|(name, _)| {
+                !SKIPPED_ON_REFRESH
+                    .iter()
+                    .any(|skipped| name.eq_ignore_ascii_case(skipped))
+            })
+            .collect();
+        metadata.headers.retain(|(stored, _)| {
+            !merged
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(stored))
+        });
+        metadata.headers.extend(merged.into_iter().cloned());
+
+        //The body is left where it is, see `CacheStorage::update_metadata`
+        let bytes = self
+            .key_and_entry_map
+            .get(key)
+            .map_or(0, |indexed| indexed.bytes);
+        let evict_time = self.stamp(&mut metadata);
+        let indexed = IndexedEntry::new(&metadata, bytes);
+        let updated = self.store.update_metadata(key, metadata).await;
+        if !matches!(updated, Ok(true)) {
+            self.forget_if_lost(key, updated.map(|_| None::<()>))?;
+            return Ok(false);
         }
-        None //Key not found
+        self.index(key, evict_time, indexed);
+        Ok(true)
+    }
+
+    // Removes a single entry; returns whether it was cached
+    pub async fn invalidate(&self, key: &str) -> Result<bool, CacheError> {
+        let indexed = self.key_and_evict_map.contains_key(key);
+        self.unindex(key);
+        self.store.delete(key).await?;
+        Ok(indexed)
+    }
+
+    // Every variant cached for the request, whatever headers it was sent with
+    pub async fn invalidate_url(&self, method: &str, url: &str) -> Result<usize, CacheError> {
+        self.invalidate_matching(|indexed| {
+            indexed.url.as_deref() == Some(url)
+                && indexed
+                    .method
+                    .as_deref()
+                    .is_some_and(|m| m.eq_ignore_ascii_case(method))
+        })
+        .await
+    }
+
+    pub async fn invalidate_prefix(&self, url_prefix: &str) -> Result<usize, CacheError> {
+        self.invalidate_matching(|indexed| {
+            indexed
+                .url
+                .as_deref()
+                .is_some_and(|url| url.starts_with(url_prefix))
+        })
+        .await
+    }
+
+    // `host` may carry a port to only purge that port
+    pub async fn invalidate_host(&self, host: &str) -> Result<usize, CacheError> {
+        self.invalidate_matching(|indexed| {
+            indexed
+                .url
+                .as_deref()
+                .is_some_and(|url| url_has_host(url, host))
+        })
+        .await
+    }
+
+    pub async fn invalidate_tag(&self, tag: &str) -> Result<usize, CacheError> {
+        self.invalidate_matching(|indexed| indexed.tags.iter().any(|t| t == tag))
+            .await
+    }
+
+    // Writes every stored HTTP entry to `path`; returns how many were written
+    pub async fn export(&self, path: &str, format: ArchiveFormat) -> Result<usize, CacheError> {
+        let keys: Vec<String> = self
+            .key_and_evict_map
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut writer = ArchiveWriter::create(path, format).await?;
+        for key in keys {
+            let entry = match self.forget_if_lost(&key, self.store.get(&key).await) {
+                Ok(Some(mut entry)) => {
+                    self.reflect_counters(&key, &mut entry.metadata);
+                    entry
+                }
+                Ok(None) | Err(CacheError::Corrupted(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            writer
+                .append(&ArchivedEntry {
+                    key: Some(key),
+                    entry,
+                })
+                .await?;
+        }
+        writer.finish().await
+    }
+
+    // Stores the archived entries, fresh for the cache's TTL. Records without
+    // a cache key are keyed by `derive_key`, see `archive::import`.
+    pub async fn import<F>(
+        &self,
+        path: &str,
+        format: ArchiveFormat,
+        derive_key: F,
+    ) -> Result<ImportReport, CacheError>
+    where
+        F: Fn(&EntryMetadata) -> Option<String>,
+    {
+        let mut report = ImportReport::default();
+        for archived in archive::read(path, format).await? {
+            let Some(key) = archive::archived_key(&archived, &derive_key) else {
+                report.skipped += 1;
+                continue;
+            };
+            self.put(&key, archived.entry).await?;
+            report.imported += 1;
+        }
+        Ok(report)
+    }
+
+    async fn invalidate_matching<F>(&self, matches: F) -> Result<usize, CacheError>
+    where
+        F: Fn(&IndexedEntry) -> bool,
+    {
+        let keys: Vec<String> = self
+            .key_and_entry_map
+            .iter()
+            .filter(|entry| matches(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in &keys {
+            self.invalidate(key).await?;
+        }
+        Ok(keys.len())
+    }
+}
+
+// Writer returned by `Cache::open_write`; indexes the entry once it is committed
+pub struct CacheWriter<'a, T: CacheStorage> {
+    cache: &'a Cache<T>,
+    key: String,
+    evict_time: u64,
+    indexed: Option<IndexedEntry>,
+    written: u64,
+    inner: EntryWriter<'a>,
+}
+
+impl<T: CacheStorage> AsyncWrite for CacheWriter<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        let mut indexed = self.indexed.take().unwrap_or_default();
+        indexed.bytes = self.written;
+        self.cache.index(&self.key, self.evict_time, indexed);
+        Poll::Ready(Ok(()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[tokio::test]
     async fn test_cache_size() {
@@ -106,9 +668,34 @@ mod tests {
         let key = "test_key";
         let value = b"test_value";
 
-        cache.put(key, value).await.unwrap();
-        let retrieved_value = cache.get(key).await;
-        assert_eq!(retrieved_value, Some(Arc::new(value.to_vec())));
+        cache
+            .put(key, CacheEntry::new(value.to_vec()))
+            .await
+            .unwrap();
+        let retrieved_value = cache.get(key).await.unwrap().unwrap();
+        assert_eq!(retrieved_value.body, value.to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_put_get_metadata() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        let key = "test_key";
+        let entry = CacheEntry::new_http(
+            200,
+            "OK",
+            vec![("Content-Type".to_string(), "text/plain".to_string())],
+            b"test_value".to_vec(),
+        );
+
+        cache.put(key, entry).await.unwrap();
+        let first = cache.get(key).await.unwrap().unwrap();
+        let second = cache.get(key).await.unwrap().unwrap();
+
+        assert_eq!(first.metadata.status, Some(200));
+        assert_eq!(first.content_type(), Some("text/plain"));
+        assert_eq!(first.metadata.expires_at, first.metadata.stored_at + 60);
+        assert_eq!(first.metadata.hit_count, 1);
+        assert_eq!(second.metadata.hit_count, 2);
     }
 
     #[tokio::test]
@@ -116,9 +703,359 @@ mod tests {
         let cache: Cache<InMemoryStorage> = Cache::new(&10, &1); // 1 second TTL
         let key = "test_key";
         let value = b"test_value";
-        cache.put(key, value).await.unwrap();
+        cache
+            .put(key, CacheEntry::new(value.to_vec()))
+            .await
+            .unwrap();
         tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-        let retrieved_value = cache.get(key).await;
+        let retrieved_value = cache.get(key).await.unwrap();
         assert_eq!(retrieved_value, None);
     }
+
+    #[tokio::test]
+    async fn test_lookup_fresh_stale_miss() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &0); // expires immediately
+        let value = b"test_value";
+        assert_eq!(cache.lookup("test_key").await.unwrap(), CacheLookup::Miss);
+
+        cache
+            .put("test_key", CacheEntry::new(value.to_vec()))
+            .await
+            .unwrap();
+        match cache.lookup("test_key").await.unwrap() {
+            CacheLookup::Stale { stale_seconds, .. } => assert_eq!(stale_seconds, 0),
+            other => panic!("Expected a stale entry, got {:?}", other),
+        }
+
+        cache.set_ttl(&60).await;
+        let headers = vec![
+            ("ETag".to_string(), "\"v2\"".to_string()),
+            ("Content-Length".to_string(), "0".to_string()),
+        ];
+        assert!(cache.refresh("test_key", &headers).await.unwrap());
+        match cache.lookup("test_key").await.unwrap() {
+            CacheLookup::Fresh(metadata) => {
+                assert_eq!(metadata.hit_count, 1);
+                assert_eq!(metadata.expires_at, metadata.stored_at + 60);
+                assert_eq!(metadata.header("ETag"), Some("\"v2\""));
+                assert_eq!(metadata.header("Content-Length"), None);
+            }
+            other => panic!("Expected a fresh entry, got {:?}", other),
+        }
+        let (_, mut reader) = cache.open_read("test_key").await.unwrap().unwrap();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, value.to_vec());
+        assert!(!cache.refresh("missing_key", &[]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stale_windows() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        assert!(!cache.within_stale_while_revalidate(0));
+        assert!(!cache.within_stale_if_error(0));
+
+        cache.set_stale_while_revalidate(&30).await;
+        cache.set_stale_if_error(&300).await;
+        assert!(cache.within_stale_while_revalidate(29));
+        assert!(!cache.within_stale_while_revalidate(30));
+        assert!(cache.within_stale_if_error(299));
+        assert!(!cache.within_stale_if_error(300));
+    }
+
+    #[tokio::test]
+    async fn test_negative_ttl() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        let not_found = CacheEntry::new_http(404, "Not Found", Vec::new(), Vec::new());
+        let mut refused = CacheEntry::new_http(502, "Bad Gateway", Vec::new(), Vec::new());
+        refused.metadata.failure = Some(UpstreamFailure::ConnectionRefused);
+
+        cache.put("404", not_found.clone()).await.unwrap();
+        let stored = cache.get("404").await.unwrap().unwrap();
+        assert_eq!(stored.metadata.expires_at, stored.metadata.stored_at + 60);
+
+        cache.set_negative_ttl(&5).await;
+        cache.put("404", not_found).await.unwrap();
+        cache.put("refused", refused).await.unwrap();
+        cache
+            .put(
+                "ok",
+                CacheEntry::new_http(200, "OK", Vec::new(), Vec::new()),
+            )
+            .await
+            .unwrap();
+        for (key, ttl) in [("404", 5), ("refused", 5), ("ok", 60)] {
+            let stored = cache.get(key).await.unwrap().unwrap();
+            assert_eq!(stored.metadata.expires_at, stored.metadata.stored_at + ttl);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_cache_survives_restart() {
+        let path = format!("/tmp/test_cache_restart/{}", uuid::Uuid::new_v4());
+        {
+            let cache = Cache::new_file_cache(&10, &60, &path);
+            cache
+                .put("test_key", CacheEntry::new(b"test_value".to_vec()))
+                .await
+                .unwrap();
+        }
+
+        let cache = Cache::new_file_cache(&10, &60, &path);
+        let retrieved_value = cache.get("test_key").await.unwrap().unwrap();
+        assert_eq!(retrieved_value.body, b"test_value".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_cache_survives_restart() {
+        let path = format!("/tmp/test_cache_restart_{}.sqlite", uuid::Uuid::new_v4());
+        {
+            let cache = Cache::new_sqlite_cache(&10, &60, &path).unwrap();
+            cache
+                .put("test_key", CacheEntry::new(b"test_value".to_vec()))
+                .await
+                .unwrap();
+        }
+
+        let cache = Cache::new_sqlite_cache(&10, &60, &path).unwrap();
+        let retrieved_value = cache.get("test_key").await.unwrap().unwrap();
+        assert_eq!(retrieved_value.body, b"test_value".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_write_read() {
+        let path = format!("/tmp/test_cache_streaming/{}", uuid::Uuid::new_v4());
+        let cache = Cache::new_file_cache(&10, &60, &path);
+
+        let mut writer = cache
+            .open_write("test_key", EntryMetadata::default())
+            .await
+            .unwrap();
+        writer.write_all(b"Hello ").await.unwrap();
+        assert_eq!(cache.lookup("test_key").await.unwrap(), CacheLookup::Miss);
+        writer.write_all(b"World!").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let (metadata, mut reader) = cache.open_read("test_key").await.unwrap().unwrap();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"Hello World!");
+        assert_eq!(metadata.expires_at, metadata.stored_at + 60);
+        assert_eq!(cache.get("test_key").await.unwrap().unwrap().body, body);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_entry_is_reported_and_forgotten() {
+        let path = format!("/tmp/test_cache_corrupted/{}", uuid::Uuid::new_v4());
+        let cache = Cache::new_file_cache(&10, &60, &path);
+        cache
+            .put("abcdef", CacheEntry::new(b"test_value".to_vec()))
+            .await
+            .unwrap();
+        std::fs::write(format!("{}/ab/cd/abcdef", path), b"garbage").unwrap();
+
+        assert!(matches!(
+            cache.lookup("abcdef").await,
+            Err(CacheError::Corrupted(_))
+        ));
+        assert_eq!(cache.lookup("abcdef").await.unwrap(), CacheLookup::Miss);
+        assert!(cache.get("abcdef").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_writer_discards_entry() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        let mut writer = cache
+            .open_write("test_key", EntryMetadata::default())
+            .await
+            .unwrap();
+        writer.write_all(b"partial").await.unwrap();
+        drop(writer);
+
+        assert_eq!(cache.lookup("test_key").await.unwrap(), CacheLookup::Miss);
+        assert!(cache.open_read("test_key").await.unwrap().is_none());
+    }
+
+    fn request_entry(method: &str, url: &str, tags: &[&str]) -> CacheEntry {
+        let mut entry = CacheEntry::new(url.as_bytes().to_vec());
+        entry.metadata.method = Some(method.to_string());
+        entry.metadata.url = Some(url.to_string());
+        entry.metadata.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        let entries = [
+            ("a1", request_entry("GET", "http://a.com/x", &["deploy-1"])),
+            ("a2", request_entry("GET", "http://a.com/x", &[])), //other header variant
+            ("a3", request_entry("HEAD", "http://a.com/x", &[])),
+            (
+                "a4",
+                request_entry("GET", "http://a.com:8080/y", &["deploy-1"]),
+            ),
+            (
+                "b1",
+                request_entry("GET", "http://b.com/x/1", &["deploy-2"]),
+            ),
+            ("b2", request_entry("GET", "http://b.com/x/2", &[])),
+            ("b3", request_entry("GET", "http://b.com/z", &[])),
+        ];
+        for (key, entry) in entries {
+            cache.put(key, entry).await.unwrap();
+        }
+
+        assert_eq!(
+            cache.invalidate_url("get", "http://a.com/x").await.unwrap(),
+            2
+        );
+        assert!(cache.get("a1").await.unwrap().is_none());
+        assert!(cache.get("a3").await.unwrap().is_some());
+
+        assert_eq!(cache.invalidate_tag("deploy-2").await.unwrap(), 1);
+        assert_eq!(cache.invalidate_prefix("http://b.com/x/").await.unwrap(), 1);
+        assert!(cache.get("b3").await.unwrap().is_some());
+
+        assert_eq!(cache.invalidate_host("A.com").await.unwrap(), 2);
+        assert!(cache.get("a4").await.unwrap().is_none());
+
+        assert!(cache.invalidate("b3").await.unwrap());
+        assert!(!cache.invalidate("b3").await.unwrap());
+        assert_eq!(cache.lookup("b3").await.unwrap(), CacheLookup::Miss);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_after_restart() {
+        let path = format!("/tmp/test_cache_invalidate/{}", uuid::Uuid::new_v4());
+        {
+            let cache = Cache::new_file_cache(&10, &60, &path);
+            let mut writer = cache
+                .open_write(
+                    "key",
+                    request_entry("GET", "http://a.com/", &["t"]).metadata,
+                )
+                .await
+                .unwrap();
+            writer.write_all(b"body").await.unwrap();
+            writer.shutdown().await.unwrap();
+        }
+
+        let cache = Cache::new_file_cache(&10, &60, &path);
+        assert_eq!(cache.invalidate_tag("t").await.unwrap(), 1);
+        assert!(cache.open_read("key").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        cache
+            .put("a", request_entry("GET", "http://A.com/x", &[]))
+            .await
+            .unwrap();
+        cache
+            .put("b", request_entry("GET", "http://b.com/x", &[]))
+            .await
+            .unwrap();
+        cache
+            .put("c", CacheEntry::new(b"no url".to_vec()))
+            .await
+            .unwrap();
+        cache
+            .put("c", CacheEntry::new(b"replaced".to_vec()))
+            .await
+            .unwrap();
+
+        assert!(cache.get("a").await.unwrap().is_some());
+        cache.lookup("b").await.unwrap();
+        cache.lookup("unknown").await.unwrap();
+        cache
+            .lookup_for_url("other", "http://a.com/y")
+            .await
+            .unwrap();
+        cache.invalidate("b").await.unwrap();
+
+        let stats = cache.stats();
+        assert_eq!(
+            stats.total,
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                expired: 0,
+                evictions: 0,
+                bytes: 14 + 8,
+                entries: 2,
+            }
+        );
+        let a = stats.hosts["a.com"];
+        assert_eq!((a.hits, a.misses, a.entries, a.bytes), (1, 1, 1, 14));
+        assert_eq!(stats.hosts["b.com"].entries, 0);
+
+        cache.set_ttl(&0).await;
+        cache
+            .put("a", request_entry("GET", "http://a.com/x", &[]))
+            .await
+            .unwrap();
+        assert!(cache.get("a").await.unwrap().is_none());
+        let a = cache.stats().hosts["a.com"];
+        assert_eq!((a.expired, a.evictions, a.entries), (1, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_stats_after_restart() {
+        let path = format!("/tmp/test_cache_stats/{}", uuid::Uuid::new_v4());
+        {
+            let cache = Cache::new_file_cache(&10, &60, &path);
+            let mut writer = cache
+                .open_write("key", request_entry("GET", "http://a.com/", &[]).metadata)
+                .await
+                .unwrap();
+            writer.write_all(b"streamed body").await.unwrap();
+            writer.shutdown().await.unwrap();
+            assert_eq!(cache.stats().total.bytes, 13);
+        }
+
+        let cache = Cache::new_file_cache(&10, &60, &path);
+        let stats = cache.stats();
+        assert_eq!((stats.total.entries, stats.total.bytes), (1, 13));
+        assert_eq!(stats.hosts["a.com"].bytes, 13);
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let cache: Cache<InMemoryStorage> = Cache::new(&10, &60);
+        let mut entry = request_entry("GET", "http://a.com/x", &["t"]);
+        entry.metadata.status = Some(200);
+        cache.put("http", entry).await.unwrap();
+        cache
+            .put("raw", CacheEntry::new(b"not an HTTP response".to_vec()))
+            .await
+            .unwrap();
+
+        for format in [ArchiveFormat::Har, ArchiveFormat::Warc] {
+            let path = format!("/tmp/test_cache_export_{}", uuid::Uuid::new_v4());
+            assert_eq!(cache.export(&path, format).await.unwrap(), 1);
+
+            let imported: Cache<InMemoryStorage> = Cache::new(&10, &60);
+            let report = imported.import(&path, format, |_| None).await.unwrap();
+            assert_eq!(report.imported, 1);
+            let entry = imported.get("http").await.unwrap().unwrap();
+            assert_eq!(entry.body, b"http://a.com/x".to_vec());
+            assert_eq!(entry.metadata.status, Some(200));
+            assert_eq!(imported.invalidate_tag("t").await.unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_url_has_host() {
+        assert!(url_has_host("http://example.com/a", "example.com"));
+        assert!(url_has_host(
+            "https://user@example.com:8443?q",
+            "example.com"
+        ));
+        assert!(url_has_host("http://example.com:8080/", "example.com:8080"));
+        assert!(!url_has_host("http://example.com:8080/", "example.com:80"));
+        assert!(!url_has_host("http://example.com.evil/", "example.com"));
+        assert!(!url_has_host("/relative", "example.com"));
+    }
 }
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Counters of one scope, either the whole cache or a single host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    // Lookups answered by a fresh entry
+    pub hits: u64,
+    // Lookups that found nothing usable
+    pub misses: u64,
+    // Lookups that found an entry past its TTL
+    pub expired: u64,
+    // Entries dropped without being invalidated: expired, lost or corrupted
+    pub evictions: u64,
+    // Body bytes of the entries currently cached
+    pub bytes: u64,
+    pub entries: u64,
+}
+
+impl CacheStats {
+    pub fn lookups(&self) -> u64 {
+        self.hits + self.misses + self.expired
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        match self.lookups() {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    pub total: CacheStats,
+    // Keyed by the URL authority, e.g. `example.com:8080`. Entries stored
+    // without a URL only count towards `total`.
+    pub hosts: HashMap<String, CacheStats>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    snapshot: Mutex<StatsSnapshot>,
+}
+
+impl Stats {
+    // Applies `update` to the total and, if known, to the host's counters
+    pub fn record<F: Fn(&mut CacheStats)>(&self, host: Option<&str>, update: F) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        update(&mut snapshot.total);
+        if let Some(host) = host {
+            update(snapshot.hosts.entry(host.to_string()).or_default());
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_total_and_host() {
+        let stats = Stats::default();
+        stats.record(Some("a.com"), |s| s.hits += 1);
+        stats.record(None, |s| s.misses += 1);
+        stats.record(Some("a.com"), |s| s.expired += 1);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total.lookups(), 3);
+        assert_eq!(snapshot.hosts.len(), 1);
+        assert_eq!(snapshot.hosts["a.com"].hit_ratio(), 0.5);
+        assert_eq!(CacheStats::default().hit_ratio(), 0.0);
+    }
+}
@@ -1,15 +1,103 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom, Write};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
+use crate::entry::{CHECKSUM_LEN, CacheEntry, EntryMetadata, header_len, read_header};
+use crate::error::CacheError;
+
+mod cassette;
+mod compressed;
+mod dedup;
+mod encrypted;
+mod sqlite;
+mod stream;
+mod tiered;
+pub use cassette::CassetteStorage;
+pub use compressed::CompressedStorage;
+pub use dedup::DedupStorage;
+pub use encrypted::EncryptedStorage;
+pub use sqlite::SqliteStorage;
+use stream::{BufferedEntryWriter, FileEntryReader, FileEntryWriter};
+pub use stream::{EntryReader, EntryWriter};
+pub use tiered::TieredStorage;
+
 #[async_trait]
-pub trait CacheStorage {
-    async fn put(&self, key: &str, value: &[u8]) -> Result<(), ()>;
-    async fn get(&self, key: &str) -> Option<Arc<Vec<u8>>>;
-    async fn delete(&self, key: &str) -> Result<(), ()>;
+pub trait CacheStorage: Send + Sync {
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError>;
+    // `Ok(None)` for keys that are not stored. Corrupted entries are dropped
+    // and reported as `CacheError::Corrupted`.
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError>;
+    // Deleting a key that is not stored is not an error
+    async fn delete(&self, key: &str) -> Result<(), CacheError>;
+
+    // Streaming access for bodies too large to hold in memory. The defaults
+    // buffer through `get`/`put`; storages that can do better override them.
+    async fn open_read(
+        &self,
+        key: &str,
+    ) -> Result<Option<(EntryMetadata, EntryReader)>, CacheError> {
+        Ok(self.get(key).await?.map(|entry| {
+            let reader: EntryReader = Box::new(std::io::Cursor::new(entry.body));
+            (entry.metadata, reader)
+        }))
+    }
+
+    // The entry is stored on `shutdown`; dropping the writer before that discards it
+    async fn open_write<'a>(
+        &'a self,
+        key: &str,
+        metadata: EntryMetadata,
+    ) -> Result<EntryWriter<'a>, CacheError> {
+        Ok(Box::new(BufferedEntryWriter::new(self, key, metadata)))
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<EntryMetadata>, CacheError> {
+        Ok(self.open_read(key).await?.map(|(metadata, _)| metadata))
+    }
+
+    // Replaces the metadata of a stored entry and keeps its body, e.g. after
+    // a `304`; `Ok(false)` for keys that are not stored. The default copies
+    // the body over, storages that can leave it alone override this.
+    async fn update_metadata(
+        &self,
+        key: &str,
+        metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        rewrite_entry(self, key, metadata).await
+    }
+
+    // Entries that survived a restart, used to rebuild the `Cache` index.
+    // Called once while constructing the cache, hence synchronous.
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        Vec::new()
+    }
+
+    // Body size of an entry found by `load_index`, if the storage can tell
+    // without reading the body. Only used for the cache's byte counters.
+    fn body_size(&self, _key: &str) -> Option<u64> {
+        None
+    }
+}
+
+async fn rewrite_entry<S: CacheStorage + ?Sized>(
+    storage: &S,
+    key: &str,
+    metadata: EntryMetadata,
+) -> Result<bool, CacheError> {
+    let Some((_, mut reader)) = storage.open_read(key).await? else {
+        return Ok(false);
+    };
+    let mut writer = storage.open_write(key, metadata).await?;
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    writer.shutdown().await?;
+    Ok(true)
 }
 
 // In-memory implementation of CacheStorage using DashMap
@@ -34,20 +122,30 @@ impl Default for InMemoryStorage {
 
 #[async_trait]
 impl CacheStorage for InMemoryStorage {
-    async fn put(&self, key: &str, value: &[u8]) -> Result<(), ()> {
-        self.storage.insert(key.to_string(), value.to_vec());
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        self.storage.insert(key.to_string(), entry.to_bytes());
         return Ok(());
     }
-    async fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
-        self.storage.get(key).map(|v| Arc::new(v.value().clone()))
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let Some(bytes) = self.storage.get(key) else {
+            return Ok(None);
+        };
+        let entry = CacheEntry::from_bytes(bytes.value());
+        drop(bytes);
+        if entry.is_none() {
+            self.storage.remove(key);
+            return Err(CacheError::Corrupted(key.to_string()));
+        }
+        Ok(entry)
     }
-    async fn delete(&self, key: &str) -> Result<(), ()> {
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
         self.storage.remove(key);
         return Ok(());
     }
 }
 
 // File-based implementation of CacheStorage could be added here
+#[derive(Debug)]
 pub struct SimpleFileStorage {
     path: String,
 }
@@ -60,6 +158,247 @@ impl SimpleFileStorage {
     }
 }
 
+impl SimpleFileStorage {
+    // Keys are encoded into a single safe file name and sharded by its first
+    // four characters, e.g. `<path>/ab/cd/abcdef...`, to keep directories small.
+    // Names too long for the file system end in a hash of the key instead, and
+    // the key itself is kept in the entry's metadata.
+    fn file_path(&self, key: &str) -> Result<PathBuf, CacheError> {
+        let file_name = encode_key(key)?;
+        let shard = format!("{:_<4}", file_name);
+        Ok(Path::new(&self.path)
+            .join(&shard[..2])
+            .join(&shard[2..4])
+            .join(file_name))
+    }
+
+    // Moves files written with the old flat `<path>/<key>` layout to their
+    // sharded location. Returns the number of migrated entries. Only scans
+    // until a migration completed, see `LAYOUT_MARKER`. Files from before
+    // entries carried metadata hold nothing but the cached bytes; they are
+    // wrapped into entries without a parsed status, which are served as
+    // stored, and kept for `LEGACY_TTL_SECONDS`.
+    pub fn migrate_flat_layout(&self) -> usize {
+        let root = Path::new(&self.path);
+        let marker = root.join(LAYOUT_MARKER);
+        if std::fs::read_to_string(&marker).is_ok_and(|version| version.trim() == LAYOUT_VERSION) {
+            return 0;
+        }
+        let mut migrated = 0;
+
+        for file_path in list_files(root) {
+            if is_temp_file(&file_path) || is_hashed_file(&file_path) {
+                continue;
+            }
+
+            let sharded = file_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(decode_key)
+                .and_then(|key| self.file_path(&key).ok());
+            if sharded.as_ref() == Some(&file_path) {
+                continue;
+            }
+
+            //Old layout: the path relative to the root is the raw key
+            let legacy_key = file_path
+                .strip_prefix(root)
+                .ok()
+                .and_then(|key| key.to_str())
+                .map(|key| key.replace(std::path::MAIN_SEPARATOR, "/"));
+            let Some((key, target)) =
+                legacy_key.and_then(|key| self.file_path(&key).ok().map(|target| (key, target)))
+            else {
+                continue;
+            };
+
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).ok();
+            }
+            let is_entry = std::fs::File::open(&file_path)
+                .ok()
+                .and_then(|mut file| CacheEntry::read_metadata(&mut file))
+                .is_some();
+            let moved = if is_entry {
+                std::fs::rename(&file_path, &target).is_ok()
+            } else {
+                convert_legacy_file(&key, &file_path, &target)
+            };
+            if moved {
+                migrated += 1;
+                if let Some(parent) = file_path.parent().filter(|parent| *parent != root) {
+                    std::fs::remove_dir(parent).ok(); //only succeeds once empty
+                }
+            }
+        }
+
+        if std::fs::create_dir_all(root).is_ok() {
+            std::fs::write(&marker, LAYOUT_VERSION).ok();
+        }
+        migrated
+    }
+}
+
+const TEMP_SUFFIX: &str = ".rlc-tmp";
+
+// Written once the flat layout has been migrated, so later starts skip the scan
+const LAYOUT_MARKER: &str = ".rlc-layout";
+const LAYOUT_VERSION: &str = "2";
+
+// How long entries converted from files without metadata are kept
+const LEGACY_TTL_SECONDS: u64 = 60 * 60;
+
+// Leaves room for the temp suffix within the usual 255 byte `NAME_MAX`
+const MAX_FILE_NAME: usize = 200;
+const HASH_SEPARATOR: char = '~';
+
+// Swaps the metadata of an entry file for `metadata_json` where the old one
+// was, padded with whitespace JSON ignores, if it fits there. The checksum is
+// linear in its input, so it is fixed up from the two metadata checksums
+// without reading the body. `None` if there is no such file.
+fn rewrite_metadata(file_path: &Path, metadata_json: &[u8]) -> std::io::Result<Option<bool>> {
+    let mut file = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some((_, old_json)) = read_header(&mut file) else {
+        return Ok(Some(false));
+    };
+    let framing = (header_len(&old_json) + CHECKSUM_LEN) as u64;
+    let Some(body_len) = file.metadata()?.len().checked_sub(framing) else {
+        return Ok(Some(false));
+    };
+    if metadata_json.len() > old_json.len() {
+        return Ok(Some(false));
+    }
+    let mut padded = metadata_json.to_vec();
+    padded.resize(old_json.len(), b' ');
+
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    file.seek(SeekFrom::End(-(CHECKSUM_LEN as i64)))?;
+    file.read_exact(&mut checksum)?;
+    let mut hasher =
+        crc32fast::Hasher::new_with_initial(crc32fast::hash(&old_json) ^ crc32fast::hash(&padded));
+    hasher.combine(&crc32fast::Hasher::new_with_initial_len(
+        u32::from_be_bytes(checksum),
+        body_len,
+    ));
+
+    file.seek(SeekFrom::Start(
+        (header_len(&old_json) - old_json.len()) as u64,
+    ))?;
+    file.write_all(&padded)?;
+    file.seek(SeekFrom::End(-(CHECKSUM_LEN as i64)))?;
+    file.write_all(&hasher.finalize().to_be_bytes())?;
+    Ok(Some(true))
+}
+
+// Writes the raw bytes of a pre-metadata file as an entry at `target`
+fn convert_legacy_file(key: &str, file_path: &Path, target: &Path) -> bool {
+    let Ok(body) = std::fs::read(file_path) else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut entry = CacheEntry::new(body);
+    entry.metadata.stored_at = now;
+    entry.metadata.expires_at = now + LEGACY_TTL_SECONDS;
+    entry.metadata.key = is_hashed_file(target).then(|| key.to_string());
+
+    let temp_path = temp_path_for(target);
+    std::fs::write(&temp_path, entry.to_bytes()).is_ok()
+        && std::fs::rename(&temp_path, target).is_ok()
+        && std::fs::remove_file(file_path).is_ok()
+}
+
+fn temp_path_for(file_path: &Path) -> PathBuf {
+    let mut temp_path = file_path.as_os_str().to_owned();
+    temp_path.push(format!(".{}{}", uuid::Uuid::new_v4(), TEMP_SUFFIX));
+    PathBuf::from(temp_path)
+}
+
+fn is_temp_file(file_path: &Path) -> bool {
+    file_path
+        .to_str()
+        .is_some_and(|path| path.ends_with(TEMP_SUFFIX))
+}
+
+// Encoded keys never contain the separator, only shortened names do
+fn is_hashed_file(file_path: &Path) -> bool {
+    file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains(HASH_SEPARATOR))
+}
+
+fn list_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(dir_entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut files = Vec::new();
+    for dir_entry in dir_entries.flatten() {
+        let path = dir_entry.path();
+        if path.is_dir() {
+            files.extend(list_files(&path));
+        } else if dir_entry.file_name() != LAYOUT_MARKER {
+            files.push(path);
+        }
+    }
+    files
+}
+
+// Keeps `[A-Za-z0-9_-]` and percent-encodes every other byte, so no key can
+// produce a path separator or `..`
+fn encode_key(key: &str) -> Result<String, CacheError> {
+    if key.is_empty() {
+        return Err(CacheError::InvalidKey(key.to_string()));
+    }
+
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if encoded.len() > MAX_FILE_NAME {
+        let hash: String = Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        encoded.truncate(MAX_FILE_NAME - hash.len() - 1);
+        encoded.push(HASH_SEPARATOR);
+        encoded.push_str(&hash);
+    }
+    Ok(encoded)
+}
+
+fn decode_key(file_name: &str) -> Option<String> {
+    if file_name.contains(HASH_SEPARATOR) {
+        return None; //the key is in the metadata
+    }
+    let mut bytes = Vec::with_capacity(file_name.len());
+    let mut chars = file_name.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
 impl Default for SimpleFileStorage {
     fn default() -> Self {
         Self::new("cache_storage")
@@ -68,28 +407,129 @@ impl Default for SimpleFileStorage {
 
 #[async_trait]
 impl CacheStorage for SimpleFileStorage {
-    async fn put(&self, key: &str, value: &[u8]) -> Result<(), ()> {
-        let file_path = format!("{}/{}", self.path, key);
-        if let Some(parent) = std::path::Path::new(&file_path).parent() {
-            fs::create_dir_all(parent).await.map_err(|_| ())?;
-        }
-        let mut file = fs::File::create(&file_path).await.map_err(|_| ())?;
-        file.write_all(value).await.map_err(|_| ())?;
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        let mut writer = self.open_write(key, entry.metadata.clone()).await?;
+        writer.write_all(&entry.body).await?;
+        writer.shutdown().await?;
         Ok(())
     }
 
-    async fn get(&self, key: &str) -> Option<Arc<Vec<u8>>> {
-        let file_path = format!("{}/{}", self.path, key);
-        match fs::read(&file_path).await {
-            Ok(data) => Some(Arc::new(data)),
-            Err(_) => None,
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let file_path = self.file_path(key)?;
+        let data = match fs::read(&file_path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match CacheEntry::from_bytes(&data) {
+            Some(mut entry) => {
+                entry.metadata.key = None;
+                Ok(Some(entry))
+            }
+            None => {
+                fs::remove_file(&file_path).await.ok();
+                Err(CacheError::Corrupted(key.to_string()))
+            }
         }
     }
 
-    async fn delete(&self, key: &str) -> Result<(), ()> {
-        let file_path = format!("{}/{}", self.path, key);
-        fs::remove_file(&file_path).await.map_err(|_| ())?;
-        Ok(())
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        let file_path = self.file_path(key)?;
+        match fs::remove_file(&file_path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn open_read(
+        &self,
+        key: &str,
+    ) -> Result<Option<(EntryMetadata, EntryReader)>, CacheError> {
+        let file_path = self.file_path(key)?;
+        let Some((mut metadata, reader)) = FileEntryReader::open(key, file_path).await? else {
+            return Ok(None);
+        };
+        metadata.key = None;
+        Ok(Some((metadata, Box::new(reader))))
+    }
+
+    // Writes to a temp file next to the target and renames it into place on
+    // shutdown, so readers only ever see complete entries
+    async fn open_write<'a>(
+        &'a self,
+        key: &str,
+        mut metadata: EntryMetadata,
+    ) -> Result<EntryWriter<'a>, CacheError> {
+        let file_path = self.file_path(key)?;
+        metadata.key = is_hashed_file(&file_path).then(|| key.to_string());
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let temp_path = temp_path_for(&file_path);
+        let writer = FileEntryWriter::create(temp_path, file_path, &metadata).await?;
+        Ok(Box::new(writer))
+    }
+
+    // In place when the new metadata is no longer than the old, which the
+    // headers of a `304` rarely make it
+    async fn update_metadata(
+        &self,
+        key: &str,
+        mut metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        let file_path = self.file_path(key)?;
+        metadata.key = is_hashed_file(&file_path).then(|| key.to_string());
+        match rewrite_metadata(&file_path, &metadata.to_json())? {
+            Some(true) => Ok(true),
+            Some(false) => rewrite_entry(self, key, metadata).await,
+            None => Ok(false),
+        }
+    }
+
+    // Every file carries its own metadata, so the directory itself is the index
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        self.migrate_flat_layout();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut index = Vec::new();
+        for file_path in list_files(Path::new(&self.path)) {
+            if is_temp_file(&file_path) {
+                std::fs::remove_file(&file_path).ok(); //left behind by an interrupted put
+                continue;
+            }
+
+            let mut metadata = std::fs::File::open(&file_path)
+                .ok()
+                .and_then(|mut file| CacheEntry::read_metadata(&mut file));
+            let key = match metadata.as_mut().and_then(|metadata| metadata.key.take()) {
+                Some(key) => Some(key),
+                None => file_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(decode_key),
+            };
+
+            match (key, metadata) {
+                (Some(key), Some(metadata)) if metadata.expires_at > now => {
+                    index.push((key, metadata))
+                }
+                _ => {
+                    std::fs::remove_file(&file_path).ok(); //expired or corrupted
+                }
+            }
+        }
+        index
+    }
+
+    fn body_size(&self, key: &str) -> Option<u64> {
+        let mut file = std::fs::File::open(self.file_path(key).ok()?).ok()?;
+        let (_, metadata_json) = read_header(&mut file)?;
+        let framing = (header_len(&metadata_json) + CHECKSUM_LEN) as u64;
+        file.metadata().ok()?.len().checked_sub(framing)
     }
 }
 
@@ -99,22 +539,22 @@ mod tests {
 
     #[tokio::test]
     async fn test_in_memory_storage_put_get() {
-        let mut storage = InMemoryStorage::new();
+        let storage = InMemoryStorage::new();
         let key = "test_key";
-        let value = b"test_value";
-        storage.put(key, value).await;
-        let retrieved_value = storage.get(key).await;
-        assert_eq!(retrieved_value, Some(Arc::new(value.to_vec())));
+        let entry = CacheEntry::new(b"test_value".to_vec());
+        storage.put(key, &entry).await.unwrap();
+        let retrieved_value = storage.get(key).await.unwrap();
+        assert_eq!(retrieved_value, Some(entry));
     }
 
     #[tokio::test]
     async fn test_in_memory_storage_delete() {
         let storage = InMemoryStorage::new();
         let key = "test_key";
-        let value = b"test_value";
-        storage.put(key, value).await;
-        storage.delete(key).await;
-        let retrieved_value = storage.get(key).await;
+        let entry = CacheEntry::new(b"test_value".to_vec());
+        storage.put(key, &entry).await.unwrap();
+        storage.delete(key).await.unwrap();
+        let retrieved_value = storage.get(key).await.unwrap();
         assert_eq!(retrieved_value, None);
     }
 
@@ -123,20 +563,271 @@ mod tests {
         let storage = SimpleFileStorage::new("/tmp/test_cache_storage");
         //key with some randomness to avoid collision
         let key = format!("{}", uuid::Uuid::new_v4());
-        let value = b"test_value";
-        storage.put(&key, value).await.unwrap();
-        let retrieved_value = storage.get(&key).await;
-        assert_eq!(retrieved_value, Some(Arc::new(value.to_vec())));
+        let entry = CacheEntry::new(b"test_value".to_vec());
+        storage.put(&key, &entry).await.unwrap();
+        let retrieved_value = storage.get(&key).await.unwrap();
+        assert_eq!(retrieved_value, Some(entry));
     }
 
     #[tokio::test]
     async fn test_file_storage_delete() {
         let storage = SimpleFileStorage::new("/tmp/test_cache_storage");
         let key = "test_key";
-        let value = b"test_value";
-        storage.put(key, value).await.unwrap();
+        let entry = CacheEntry::new(b"test_value".to_vec());
+        storage.put(key, &entry).await.unwrap();
         storage.delete(key).await.unwrap();
-        let retrieved_value = storage.get(key).await;
+        let retrieved_value = storage.get(key).await.unwrap();
         assert_eq!(retrieved_value, None);
     }
+
+    #[tokio::test]
+    async fn test_file_storage_updates_metadata_in_place() {
+        use std::os::unix::fs::MetadataExt;
+
+        let storage = SimpleFileStorage::new("/tmp/test_cache_storage");
+        let key = format!("{}", uuid::Uuid::new_v4());
+        let headers = |etag: &str| vec![("ETag".to_string(), etag.to_string())];
+        let body = vec![7u8; 100_000];
+        let entry = CacheEntry::new_http(200, "OK", headers("\"version-1\""), body.clone());
+        storage.put(&key, &entry).await.unwrap();
+        let file_path = storage.file_path(&key).unwrap();
+        let before = std::fs::metadata(&file_path).unwrap();
+
+        let mut metadata = entry.metadata.clone();
+        metadata.headers = headers("\"v2\"");
+        assert!(
+            storage
+                .update_metadata(&key, metadata.clone())
+                .await
+                .unwrap()
+        );
+        let after = std::fs::metadata(&file_path).unwrap();
+        assert_eq!((after.ino(), after.len()), (before.ino(), before.len()));
+        let updated = storage.get(&key).await.unwrap().unwrap();
+        assert_eq!(updated.metadata, metadata);
+        assert_eq!(updated.body, body);
+
+        //No longer fits, so the file is rewritten
+        metadata.headers = headers(&"x".repeat(200));
+        assert!(
+            storage
+                .update_metadata(&key, metadata.clone())
+                .await
+                .unwrap()
+        );
+        let updated = storage.get(&key).await.unwrap().unwrap();
+        assert_eq!(updated.metadata, metadata);
+        assert_eq!(updated.body, body);
+
+        assert!(
+            !storage
+                .update_metadata("missing_key", metadata)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_load_index() {
+        let path = format!("/tmp/test_cache_storage_index/{}", uuid::Uuid::new_v4());
+        let storage = SimpleFileStorage::new(&path);
+
+        let mut valid = CacheEntry::new(b"valid".to_vec());
+        valid.metadata.expires_at = u64::MAX;
+        storage.put("valid", &valid).await.unwrap();
+        storage.put("nested/valid", &valid).await.unwrap();
+
+        let mut expired = CacheEntry::new(b"expired".to_vec());
+        expired.metadata.expires_at = 1;
+        storage.put("expired", &expired).await.unwrap();
+
+        //flat files are baseline entries, so the damaged one goes where an entry would be
+        fs::create_dir_all(format!("{}/co/rr", path)).await.unwrap();
+        fs::write(format!("{}/co/rr/corrupted", path), b"not an entry")
+            .await
+            .unwrap();
+        fs::write(format!("{}/va/li/valid.leftover.rlc-tmp", path), b"partial")
+            .await
+            .unwrap();
+
+        let mut index = storage.load_index();
+        index.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            index,
+            vec![
+                ("nested/valid".to_string(), valid.metadata.clone()),
+                ("valid".to_string(), valid.metadata.clone()),
+            ]
+        );
+        assert_eq!(storage.get("expired").await.unwrap(), None);
+        assert!(
+            fs::metadata(format!("{}/co/rr/corrupted", path))
+                .await
+                .is_err()
+        );
+        assert_eq!(list_files(Path::new(&path)).len(), 2);
+    }
+
+    #[test]
+    fn test_key_encoding() {
+        let key = "../../etc/passwd";
+        let encoded = encode_key(key).unwrap();
+        assert_eq!(encoded, "%2E%2E%2F%2E%2E%2Fetc%2Fpasswd");
+        assert_eq!(decode_key(&encoded).unwrap(), key);
+        assert!(matches!(encode_key(""), Err(CacheError::InvalidKey(_))));
+        assert_eq!(decode_key("%2"), None);
+
+        let long_key = "/".repeat(300);
+        let encoded = encode_key(&long_key).unwrap();
+        assert_eq!(encoded.len(), MAX_FILE_NAME);
+        assert_ne!(encoded, encode_key(&"/".repeat(301)).unwrap());
+        assert_eq!(decode_key(&encoded), None);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_hashes_long_keys() {
+        let path = format!("/tmp/test_cache_storage_long/{}", uuid::Uuid::new_v4());
+        let storage = SimpleFileStorage::new(&path);
+        let key = format!("GET http://example.com/{}", "%".repeat(200));
+        let mut entry = CacheEntry::new(b"test_value".to_vec());
+        entry.metadata.expires_at = u64::MAX;
+
+        storage.put(&key, &entry).await.unwrap();
+        assert_eq!(storage.get(&key).await.unwrap(), Some(entry.clone()));
+        assert_eq!(storage.body_size(&key), Some(10));
+
+        let index = storage.load_index();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].0, key);
+        assert_eq!(index[0].1.key, None);
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_sharded_layout() {
+        let path = format!("/tmp/test_cache_storage_sharded/{}", uuid::Uuid::new_v4());
+        let storage = SimpleFileStorage::new(&path);
+        let entry = CacheEntry::new(b"test_value".to_vec());
+
+        storage.put("abcdef", &entry).await.unwrap();
+        storage.put("../escape", &entry).await.unwrap();
+        storage.put("a", &entry).await.unwrap();
+
+        let mut files = list_files(Path::new(&path));
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                Path::new(&path).join("%2/E%/%2E%2E%2Fescape"),
+                Path::new(&path).join("a_/__/a"),
+                Path::new(&path).join("ab/cd/abcdef"),
+            ]
+        );
+        assert_eq!(storage.get("../escape").await.unwrap(), Some(entry));
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_migrates_flat_layout() {
+        let path = format!("/tmp/test_cache_storage_migrate/{}", uuid::Uuid::new_v4());
+        let mut entry = CacheEntry::new(b"test_value".to_vec());
+        entry.metadata.expires_at = u64::MAX;
+
+        fs::create_dir_all(&path).await.unwrap();
+        fs::write(format!("{}/abcdef", path), entry.to_bytes())
+            .await
+            .unwrap();
+
+        let storage = SimpleFileStorage::new(&path);
+        assert_eq!(storage.migrate_flat_layout(), 1);
+        assert!(
+            fs::metadata(format!("{}/{}", path, LAYOUT_MARKER))
+                .await
+                .is_ok()
+        );
+
+        //Files dropped in flat after the marker was written are left alone
+        fs::write(format!("{}/ghijkl", path), entry.to_bytes())
+            .await
+            .unwrap();
+        assert_eq!(storage.migrate_flat_layout(), 0);
+        assert!(fs::metadata(format!("{}/ghijkl", path)).await.is_ok());
+        assert_eq!(storage.get("abcdef").await.unwrap(), Some(entry));
+        assert!(fs::metadata(format!("{}/abcdef", path)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_migrates_baseline_files() {
+        let path = format!("/tmp/test_cache_storage_legacy/{}", uuid::Uuid::new_v4());
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi".to_vec();
+        fs::create_dir_all(format!("{}/GET", path)).await.unwrap();
+        fs::write(format!("{}/GET/example.com", path), &response)
+            .await
+            .unwrap();
+
+        let storage = SimpleFileStorage::new(&path);
+        let index = storage.load_index();
+        assert_eq!(index.len(), 1);
+        let (key, metadata) = &index[0];
+        assert_eq!(key, "GET/example.com");
+        assert_eq!(metadata.status, None);
+        assert_eq!(metadata.expires_at, metadata.stored_at + LEGACY_TTL_SECONDS);
+        assert_eq!(
+            storage.get("GET/example.com").await.unwrap().unwrap().body,
+            response
+        );
+        assert!(fs::metadata(format!("{}/GET", path)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_drops_corrupted_entries() {
+        let path = format!("/tmp/test_cache_storage_corrupt/{}", uuid::Uuid::new_v4());
+        let storage = SimpleFileStorage::new(&path);
+        storage
+            .put("abcdef", &CacheEntry::new(b"test_value".to_vec()))
+            .await
+            .unwrap();
+
+        let file_path = format!("{}/ab/cd/abcdef", path);
+        let mut data = fs::read(&file_path).await.unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&file_path, data).await.unwrap();
+
+        assert!(matches!(
+            storage.get("abcdef").await,
+            Err(CacheError::Corrupted(_))
+        ));
+        assert_eq!(storage.get("abcdef").await.unwrap(), None);
+        assert!(fs::metadata(&file_path).await.is_err());
+        assert_eq!(list_files(Path::new(&path)), Vec::<PathBuf>::new());
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_streaming_detects_corruption() {
+        use tokio::io::AsyncReadExt;
+
+        let path = format!("/tmp/test_cache_storage_stream/{}", uuid::Uuid::new_v4());
+        let storage = SimpleFileStorage::new(&path);
+        let mut writer = storage
+            .open_write("abcdef", EntryMetadata::default())
+            .await
+            .unwrap();
+        writer.write_all(b"test_value").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let (_, mut reader) = storage.open_read("abcdef").await.unwrap().unwrap();
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"test_value");
+
+        let file_path = format!("{}/ab/cd/abcdef", path);
+        let mut data = fs::read(&file_path).await.unwrap();
+        let body_start = data.len() - CHECKSUM_LEN - body.len();
+        data[body_start] ^= 0xff;
+        fs::write(&file_path, data).await.unwrap();
+
+        let (_, mut reader) = storage.open_read("abcdef").await.unwrap().unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(fs::metadata(&file_path).await.is_err());
+    }
 }
@@ -0,0 +1,317 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use super::CacheStorage;
+use crate::entry::{CacheEntry, EntryMetadata, UpstreamFailure};
+use crate::error::CacheError;
+
+// A recorded session as one pretty-printed JSON file, in recording order, meant
+// to be checked in next to the tests replaying it. Timestamps and hit counts
+// are left out so re-recording only shows real changes; loaded entries never
+// expire. Every change rewrites the whole file.
+#[derive(Debug)]
+pub struct CassetteStorage {
+    path: String,
+    interactions: Mutex<Vec<(String, CacheEntry)>>,
+    //Held while writing the file so an older snapshot never lands last
+    saving: tokio::sync::Mutex<()>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cassette {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    // The request headers the key was made of
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    request_headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failure: Option<UpstreamFailure>,
+    body: String,
+    // `base64` for bodies that are not UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+impl CassetteStorage {
+    // Starts an empty cassette, replacing whatever is at `path`
+    pub async fn create(path: &str) -> Result<Self, CacheError> {
+        tokio::fs::write(path, Self::to_json(&[])?).await?;
+        Ok(CassetteStorage {
+            path: path.to_string(),
+            interactions: Mutex::new(Vec::new()),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    // Loads a recorded cassette, which must exist
+    pub async fn open(path: &str) -> Result<Self, CacheError> {
+        let cassette: Cassette = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let interactions = cassette
+            .interactions
+            .into_iter()
+            .map(|interaction| Self::from_interaction(interaction, now))
+            .collect::<Result<_, _>>()?;
+        Ok(CassetteStorage {
+            path: path.to_string(),
+            interactions: Mutex::new(interactions),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn to_json(interactions: &[(String, CacheEntry)]) -> Result<Vec<u8>, CacheError> {
+        let cassette = Cassette {
+            interactions: interactions
+                .iter()
+                .map(|(key, entry)| Self::to_interaction(key, entry))
+                .collect(),
+        };
+        let mut json = serde_json::to_vec_pretty(&cassette)?;
+        json.push(b'\n');
+        Ok(json)
+    }
+
+    // Applies `change` and writes the result, unless `change` returns false
+    async fn save<F>(&self, change: F) -> Result<(), CacheError>
+    where
+        F: FnOnce(&mut Vec<(String, CacheEntry)>) -> bool,
+    {
+        let _saving = self.saving.lock().await;
+        let json = {
+            let mut interactions = self.interactions.lock().unwrap();
+            if !change(&mut interactions) {
+                return Ok(());
+            }
+            Self::to_json(&interactions)?
+        };
+        tokio::fs::write(&self.path, json).await?;
+        Ok(())
+    }
+
+    fn to_interaction(key: &str, entry: &CacheEntry) -> Interaction {
+        let metadata = &entry.metadata;
+        let (body, encoding) = Self::encode_body(&entry.body);
+        let (request_body, request_encoding) = match &metadata.request_body {
+            Some(request_body) => {
+                let (request_body, request_encoding) = Self::encode_body(request_body);
+                (Some(request_body), request_encoding)
+            }
+            None => (None, None),
+        };
+        Interaction {
+            key: key.to_string(),
+            method: metadata.method.clone(),
+            url: metadata.url.clone(),
+            request_headers: metadata.request_headers.clone(),
+            request_body,
+            request_encoding,
+            status: metadata.status,
+            reason: metadata.reason.clone(),
+            headers: metadata.headers.clone(),
+            tags: metadata.tags.clone(),
+            failure: metadata.failure,
+            body,
+            encoding,
+        }
+    }
+
+    // `base64` is set for bodies that are not UTF-8
+    fn encode_body(body: &[u8]) -> (String, Option<String>) {
+        match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), None),
+            Err(_) => (BASE64.encode(body), Some("base64".to_string())),
+        }
+    }
+
+    fn decode_body(body: String, encoding: Option<&str>) -> Result<Vec<u8>, CacheError> {
+        match encoding {
+            Some("base64") => BASE64
+                .decode(&body)
+                .map_err(|e| CacheError::Serialization(format!("Invalid cassette body: {}", e))),
+            _ => Ok(body.into_bytes()),
+        }
+    }
+
+    fn from_interaction(
+        interaction: Interaction,
+        now: u64,
+    ) -> Result<(String, CacheEntry), CacheError> {
+        let body = Self::decode_body(interaction.body, interaction.encoding.as_deref())?;
+        let request_body = interaction
+            .request_body
+            .map(|body| Self::decode_body(body, interaction.request_encoding.as_deref()))
+            .transpose()?;
+        let metadata = EntryMetadata {
+            stored_at: now,
+            expires_at: u64::MAX,
+            status: interaction.status,
+            reason: interaction.reason,
+            headers: interaction.headers,
+            method: interaction.method,
+            url: interaction.url,
+            request_headers: interaction.request_headers,
+            request_body,
+            tags: interaction.tags,
+            failure: interaction.failure,
+            ..EntryMetadata::default()
+        };
+        Ok((interaction.key, CacheEntry { metadata, body }))
+    }
+}
+
+#[async_trait]
+impl CacheStorage for CassetteStorage {
+    // A key recorded again keeps its place in the cassette
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        self.save(|interactions| {
+            match interactions.iter_mut().find(|(k, _)| k == key) {
+                Some((_, recorded)) => *recorded = entry.clone(),
+                None => interactions.push((key.to_string(), entry.clone())),
+            }
+            true
+        })
+        .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let interactions = self.interactions.lock().unwrap();
+        Ok(interactions
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, entry)| entry.clone()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.save(|interactions| {
+            let count = interactions.len();
+            interactions.retain(|(k, _)| k != key);
+            interactions.len() != count
+        })
+        .await
+    }
+
+    async fn update_metadata(
+        &self,
+        key: &str,
+        metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        let mut updated = false;
+        self.save(|interactions| {
+            if let Some((_, recorded)) = interactions.iter_mut().find(|(k, _)| k == key) {
+                recorded.metadata = metadata;
+                updated = true;
+            }
+            updated
+        })
+        .await?;
+        Ok(updated)
+    }
+
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        let interactions = self.interactions.lock().unwrap();
+        interactions
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.metadata.clone()))
+            .collect()
+    }
+
+    fn body_size(&self, key: &str) -> Option<u64> {
+        let interactions = self.interactions.lock().unwrap();
+        interactions
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, entry)| entry.body.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &[u8]) -> CacheEntry {
+        let mut entry = CacheEntry::new_http(
+            200,
+            "OK",
+            vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body.to_vec(),
+        );
+        entry.metadata.method = Some("GET".to_string());
+        entry.metadata.url = Some("http://example.com/".to_string());
+        entry.metadata.request_headers = vec![("Accept".to_string(), "*/*".to_string())];
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_cassette_roundtrip_keeps_order() {
+        let path = "/tmp/test_cassette_roundtrip.json";
+        let storage = CassetteStorage::create(path).await.unwrap();
+        storage.put("b", &entry(b"first")).await.unwrap();
+        let mut posted = entry(&[0xff, 0x00]);
+        posted.metadata.method = Some("POST".to_string());
+        posted.metadata.request_body = Some(vec![0xfe, b'q']);
+        storage.put("a", &posted).await.unwrap();
+        storage.put("c", &entry(b"third")).await.unwrap();
+        storage.put("b", &entry(b"rerecorded")).await.unwrap();
+        storage.delete("c").await.unwrap();
+
+        let json = std::fs::read_to_string(path).unwrap();
+        assert!(json.contains("\"body\": \"rerecorded\""));
+        assert!(!json.contains("stored_at"));
+
+        let replayed = CassetteStorage::open(path).await.unwrap();
+        let index = replayed.load_index();
+        let keys: Vec<&str> = index.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["b", "a"]);
+        assert_eq!(index[0].1.expires_at, u64::MAX);
+        assert_eq!(replayed.body_size("b"), Some(10));
+        {
+            //Exact even while a change is being written
+            let _saving = replayed.saving.lock().await;
+            assert_eq!(replayed.load_index().len(), 2);
+            assert_eq!(replayed.body_size("a"), Some(2));
+        }
+        let binary = replayed.get("a").await.unwrap().unwrap();
+        assert_eq!(binary.body, vec![0xff, 0x00]);
+        assert_eq!(binary.metadata.url.as_deref(), Some("http://example.com/"));
+        assert_eq!(
+            binary.metadata.request_headers,
+            entry(b"").metadata.request_headers
+        );
+        assert_eq!(binary.metadata.request_body, Some(vec![0xfe, b'q']));
+        assert!(json.contains("\"request_encoding\": \"base64\""));
+
+        assert!(
+            CassetteStorage::open("/tmp/test_cassette_missing.json")
+                .await
+                .is_err()
+        );
+    }
+}
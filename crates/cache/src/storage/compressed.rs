@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use super::CacheStorage;
+use crate::entry::{CacheEntry, Compression, EntryMetadata};
+use crate::error::CacheError;
+
+const ZSTD_LEVEL: i32 = 3;
+
+// Compresses bodies on `put` and decompresses them on `get` around any other
+// storage. The algorithm is recorded per entry, so entries stored uncompressed
+// or with another algorithm keep reading fine. Bodies that do not shrink are
+// stored as they are.
+#[derive(Debug)]
+pub struct CompressedStorage<S: CacheStorage> {
+    inner: S,
+    compression: Compression,
+    // Uncompressed lengths seen by `load_index`, handed out once by `body_size`
+    body_lens: Mutex<HashMap<String, u64>>,
+}
+
+impl<S: CacheStorage> CompressedStorage<S> {
+    pub fn new(inner: S, compression: Compression) -> Self {
+        CompressedStorage {
+            inner,
+            compression,
+            body_lens: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn compress(compression: Compression, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Compression::Zstd => zstd::encode_all(body, ZSTD_LEVEL),
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn decompress(compression: Compression, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Compression::Zstd => zstd::decode_all(body),
+        Compression::Gzip => {
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(body).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+    }
+}
+
+#[async_trait]
+impl<S: CacheStorage> CacheStorage for CompressedStorage<S> {
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        let compressed = compress(self.compression, &entry.body)?;
+        if compressed.len() >= entry.body.len() {
+            let mut entry = entry.clone();
+            entry.metadata.compression = None;
+            entry.metadata.uncompressed_len = None;
+            return self.inner.put(key, &entry).await;
+        }
+
+        let mut metadata = entry.metadata.clone();
+        metadata.compression = Some(self.compression);
+        metadata.uncompressed_len = Some(entry.body.len() as u64);
+        let stored = CacheEntry {
+            metadata,
+            body: compressed,
+        };
+        self.inner.put(key, &stored).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let Some(mut entry) = self.inner.get(key).await? else {
+            return Ok(None);
+        };
+        entry.metadata.uncompressed_len = None;
+        if let Some(compression) = entry.metadata.compression.take() {
+            match decompress(compression, &entry.body) {
+                Ok(body) => entry.body = body,
+                Err(_) => {
+                    self.inner.delete(key).await.ok();
+                    return Err(CacheError::Corrupted(key.to_string()));
+                }
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.inner.delete(key).await
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<EntryMetadata>, CacheError> {
+        let metadata = self.inner.metadata(key).await?;
+        Ok(metadata.map(|metadata| EntryMetadata {
+            compression: None,
+            uncompressed_len: None,
+            ..metadata
+        }))
+    }
+
+    // The body stays compressed as it is
+    async fn update_metadata(
+        &self,
+        key: &str,
+        mut metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        let Some(stored) = self.inner.metadata(key).await? else {
+            return Ok(false);
+        };
+        metadata.compression = stored.compression;
+        metadata.uncompressed_len = stored.uncompressed_len;
+        self.inner.update_metadata(key, metadata).await
+    }
+
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        let index = self.inner.load_index();
+        let mut body_lens = self.body_lens.lock().unwrap();
+        for (key, metadata) in &index {
+            if let Some(len) = metadata.uncompressed_len {
+                body_lens.insert(key.clone(), len);
+            }
+        }
+        index
+    }
+
+    // Entries stored uncompressed are as large as the inner storage says
+    fn body_size(&self, key: &str) -> Option<u64> {
+        let len = self.body_lens.lock().unwrap().remove(key);
+        len.or_else(|| self.inner.body_size(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    #[tokio::test]
+    async fn test_compressed_storage_roundtrip() {
+        for compression in [Compression::Zstd, Compression::Gzip] {
+            let storage = CompressedStorage::new(InMemoryStorage::new(), compression);
+            let entry = CacheEntry::new(b"Hello World! ".repeat(100));
+
+            storage.put("key", &entry).await.unwrap();
+            let stored = storage.inner.get("key").await.unwrap().unwrap();
+            assert_eq!(stored.metadata.compression, Some(compression));
+            assert!(stored.body.len() < entry.body.len());
+
+            assert_eq!(storage.get("key").await.unwrap(), Some(entry.clone()));
+            assert_eq!(
+                storage.metadata("key").await.unwrap(),
+                Some(entry.metadata.clone())
+            );
+
+            let mut refreshed = entry.clone();
+            refreshed.metadata.expires_at = 42;
+            assert!(
+                storage
+                    .update_metadata("key", refreshed.metadata.clone())
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(storage.get("key").await.unwrap(), Some(refreshed));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compressed_storage_mixed_entries() {
+        let storage = CompressedStorage::new(InMemoryStorage::new(), Compression::Zstd);
+        let plain = CacheEntry::new(b"stored before compression".to_vec());
+        storage.inner.put("plain", &plain).await.unwrap();
+        assert_eq!(storage.get("plain").await.unwrap(), Some(plain));
+
+        //Written by a cache configured for gzip
+        let gzip = CompressedStorage::new(InMemoryStorage::new(), Compression::Gzip);
+        let entry = CacheEntry::new(b"gzip ".repeat(100));
+        gzip.put("key", &entry).await.unwrap();
+        let storage = CompressedStorage::new(gzip.inner, Compression::Zstd);
+        assert_eq!(storage.get("key").await.unwrap(), Some(entry));
+
+        //Incompressible bodies are kept as they are
+        storage
+            .put("tiny", &CacheEntry::new(b"x".to_vec()))
+            .await
+            .unwrap();
+        let stored = storage.inner.get("tiny").await.unwrap().unwrap();
+        assert_eq!(stored.metadata.compression, None);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_storage_body_size_survives_restart() {
+        use crate::Cache;
+        use crate::storage::SimpleFileStorage;
+
+        let path = format!("/tmp/test_compressed_storage_size/{}", uuid::Uuid::new_v4());
+        let open = || CompressedStorage::new(SimpleFileStorage::new(&path), Compression::Zstd);
+
+        let cache = Cache::with_storage(&10, &60, open());
+        cache
+            .put("big", CacheEntry::new(b"Hello World! ".repeat(100)))
+            .await
+            .unwrap();
+        cache
+            .put("tiny", CacheEntry::new(b"x".to_vec()))
+            .await
+            .unwrap();
+        drop(cache);
+
+        let cache = Cache::with_storage(&10, &60, open());
+        assert_eq!(cache.stats().total.entries, 2);
+        assert_eq!(cache.stats().total.bytes, 1301);
+    }
+}
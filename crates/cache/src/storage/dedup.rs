@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::CacheStorage;
+use crate::entry::{CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+
+// Stores every distinct body once, keyed by its SHA-256, in `bodies`. Entries in
+// `entries` keep their metadata and point at their body via `content_hash`.
+// Bodies are reference counted and deleted with their last entry.
+#[derive(Debug)]
+pub struct DedupStorage<S: CacheStorage> {
+    entries: S,
+    bodies: S,
+    //content hash -> number of entries pointing at it
+    references: std::sync::Mutex<HashMap<String, u64>>,
+    //Held across storage calls so a body is never deleted while it is being
+    //read or re-referenced
+    busy: Mutex<()>,
+    //bodies nothing pointed at on startup, deleted on the next write
+    orphans: std::sync::Mutex<Vec<String>>,
+}
+
+impl<S: CacheStorage> DedupStorage<S> {
+    pub fn new(entries: S, bodies: S) -> Self {
+        DedupStorage {
+            entries,
+            bodies,
+            references: std::sync::Mutex::new(HashMap::new()),
+            busy: Mutex::new(()),
+            orphans: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn content_hash(body: &[u8]) -> String {
+        Sha256::digest(body)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Callers hold `busy`
+    async fn release(&self, hash: &str) {
+        let unreferenced = {
+            let mut references = self.references.lock().unwrap();
+            let Some(count) = references.get_mut(hash) else {
+                return;
+            };
+            *count -= 1;
+            *count == 0 && references.remove(hash).is_some()
+        };
+        if unreferenced {
+            self.bodies.delete(hash).await.ok();
+        }
+    }
+
+    // Callers hold `busy`
+    async fn remove(&self, key: &str) -> Result<(), CacheError> {
+        let hash = self
+            .entries
+            .metadata(key)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|metadata| metadata.content_hash);
+        self.entries.delete(key).await?;
+        if let Some(hash) = hash {
+            self.release(&hash).await;
+        }
+        Ok(())
+    }
+
+    async fn delete_orphans(&self) {
+        let orphans = std::mem::take(&mut *self.orphans.lock().unwrap());
+        for hash in orphans {
+            self.bodies.delete(&hash).await.ok();
+        }
+    }
+}
+
+#[async_trait]
+impl<S: CacheStorage> CacheStorage for DedupStorage<S> {
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        self.delete_orphans().await;
+        let hash = Self::content_hash(&entry.body);
+
+        let _busy = self.busy.lock().await;
+        let previous = self
+            .entries
+            .metadata(key)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|metadata| metadata.content_hash);
+        let stored = self.references.lock().unwrap().contains_key(&hash);
+        if !stored {
+            let mut body = CacheEntry::new(entry.body.clone());
+            body.metadata.expires_at = u64::MAX; //lives as long as it is referenced
+            self.bodies.put(&hash, &body).await?;
+        }
+
+        let mut pointer = CacheEntry::new(Vec::new());
+        pointer.metadata = entry.metadata.clone();
+        pointer.metadata.content_hash = Some(hash.clone());
+        if let Err(e) = self.entries.put(key, &pointer).await {
+            if !stored {
+                self.bodies.delete(&hash).await.ok();
+            }
+            return Err(e);
+        }
+
+        *self.references.lock().unwrap().entry(hash).or_insert(0) += 1;
+        if let Some(previous) = previous {
+            self.release(&previous).await;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        //A concurrent put must not release the body between the two reads
+        let _busy = self.busy.lock().await;
+        let Some(mut entry) = self.entries.get(key).await? else {
+            return Ok(None);
+        };
+        if let Some(hash) = entry.metadata.content_hash.take() {
+            match self.bodies.get(&hash).await {
+                Ok(Some(body)) => entry.body = body.body,
+                //A pointer without its body is as good as a corrupted entry
+                Ok(None) | Err(CacheError::Corrupted(_)) => {
+                    self.remove(key).await.ok();
+                    return Err(CacheError::Corrupted(key.to_string()));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.delete_orphans().await;
+        let _busy = self.busy.lock().await;
+        self.remove(key).await
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<EntryMetadata>, CacheError> {
+        let metadata = self.entries.metadata(key).await?;
+        Ok(metadata.map(|metadata| EntryMetadata {
+            content_hash: None,
+            ..metadata
+        }))
+    }
+
+    // Only the pointer is rewritten, the shared body stays as it is
+    async fn update_metadata(
+        &self,
+        key: &str,
+        mut metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        let _busy = self.busy.lock().await;
+        let Some(stored) = self.entries.metadata(key).await? else {
+            return Ok(false);
+        };
+        metadata.content_hash = stored.content_hash;
+        self.entries.update_metadata(key, metadata).await
+    }
+
+    // Reference counts are rebuilt from the entries that survived
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        let index = self.entries.load_index();
+        let mut references = HashMap::new();
+        for (_, metadata) in &index {
+            if let Some(hash) = &metadata.content_hash {
+                *references.entry(hash.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let orphans = self
+            .bodies
+            .load_index()
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .filter(|hash| !references.contains_key(hash))
+            .collect();
+        *self.orphans.lock().unwrap() = orphans;
+        *self.references.lock().unwrap() = references;
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InMemoryStorage, SimpleFileStorage};
+
+    #[tokio::test]
+    async fn test_dedup_storage_shares_bodies() {
+        let storage = DedupStorage::new(InMemoryStorage::new(), InMemoryStorage::new());
+        let body = b"identical body".to_vec();
+        let hash = DedupStorage::<InMemoryStorage>::content_hash(&body);
+
+        storage
+            .put("a", &CacheEntry::new(body.clone()))
+            .await
+            .unwrap();
+        storage
+            .put("b", &CacheEntry::new(body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(storage.references.lock().unwrap().get(&hash), Some(&2));
+        assert_eq!(
+            storage.get("b").await.unwrap(),
+            Some(CacheEntry::new(body.clone()))
+        );
+
+        let metadata = EntryMetadata {
+            expires_at: 42,
+            ..EntryMetadata::default()
+        };
+        assert!(storage.update_metadata("b", metadata).await.unwrap());
+        let refreshed = storage.get("b").await.unwrap().unwrap();
+        assert_eq!(
+            (refreshed.metadata.expires_at, refreshed.body),
+            (42, body.clone())
+        );
+        assert_eq!(storage.references.lock().unwrap().get(&hash), Some(&2));
+
+        storage.delete("a").await.unwrap();
+        assert_eq!(storage.get("a").await.unwrap(), None);
+        assert!(storage.bodies.get(&hash).await.unwrap().is_some());
+
+        //Overwriting the last reference drops the old body
+        storage
+            .put("b", &CacheEntry::new(b"other".to_vec()))
+            .await
+            .unwrap();
+        assert!(storage.bodies.get(&hash).await.unwrap().is_none());
+        assert_eq!(
+            storage.get("b").await.unwrap().unwrap().body,
+            b"other".to_vec()
+        );
+
+        storage.delete("b").await.unwrap();
+        assert!(storage.references.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_dedup_storage_get_during_overwrite() {
+        let storage = std::sync::Arc::new(DedupStorage::new(
+            InMemoryStorage::new(),
+            InMemoryStorage::new(),
+        ));
+        storage
+            .put("key", &CacheEntry::new(b"version 0".to_vec()))
+            .await
+            .unwrap();
+
+        let writer = storage.clone();
+        let writes = tokio::spawn(async move {
+            for version in 1..200 {
+                let body = format!("version {}", version).into_bytes();
+                writer.put("key", &CacheEntry::new(body)).await.unwrap();
+            }
+        });
+        for _ in 0..200 {
+            //Never mistaken for a pointer whose body is gone
+            assert!(storage.get("key").await.unwrap().is_some());
+        }
+        writes.await.unwrap();
+        assert_eq!(
+            storage.get("key").await.unwrap().unwrap().body,
+            b"version 199".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dedup_storage_rebuilds_references() {
+        let path = "/tmp/test_dedup_storage";
+        std::fs::remove_dir_all(path).ok();
+        let open = || {
+            DedupStorage::new(
+                SimpleFileStorage::new(&format!("{}/entries", path)),
+                SimpleFileStorage::new(&format!("{}/bodies", path)),
+            )
+        };
+
+        let mut entry = CacheEntry::new(b"shared".to_vec());
+        entry.metadata.expires_at = u64::MAX;
+        let storage = open();
+        storage.put("a", &entry).await.unwrap();
+        storage.put("b", &entry).await.unwrap();
+        let mut orphan = CacheEntry::new(b"orphan".to_vec());
+        orphan.metadata.expires_at = u64::MAX;
+        storage.bodies.put("orphan", &orphan).await.unwrap();
+        drop(storage);
+
+        let storage = open();
+        assert_eq!(storage.load_index().len(), 2);
+        storage.delete("a").await.unwrap();
+        assert!(storage.bodies.get("orphan").await.unwrap().is_none());
+        assert_eq!(
+            storage.get("b").await.unwrap().unwrap().body,
+            b"shared".to_vec()
+        );
+    }
+}
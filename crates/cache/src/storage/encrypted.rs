@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+
+use super::CacheStorage;
+use crate::entry::{CacheEntry, Encryption, EntryMetadata};
+use crate::error::CacheError;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+// Seals entries with ChaCha20-Poly1305 around any other storage. Only the
+// timestamps stay readable. Sealed body: nonce | ciphertext of (u32 metadata
+// length | metadata JSON | body), authenticated against the cache key so
+// entries cannot be swapped between keys. What the index needs after a restart
+// is sealed the same way into `EntryMetadata::sealed_index`, see `SealedIndex`.
+// Entries failing authentication are deleted, not served.
+pub struct EncryptedStorage<S: CacheStorage> {
+    inner: S,
+    cipher: ChaCha20Poly1305,
+    // Plaintext body lengths opened by `load_index`, handed out once by `body_size`
+    body_lens: Mutex<HashMap<String, u64>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedIndex {
+    method: Option<String>,
+    url: Option<String>,
+    tags: Vec<String>,
+    body_len: u64,
+}
+
+impl<S: CacheStorage> std::fmt::Debug for EncryptedStorage<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedStorage").finish_non_exhaustive() //never print the key
+    }
+}
+
+impl<S: CacheStorage> EncryptedStorage<S> {
+    pub fn new(inner: S, key: &[u8; KEY_LEN]) -> Self {
+        EncryptedStorage {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            body_lens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The file holds either the raw 32 key bytes or them hex encoded
+    pub fn from_key_file(inner: S, path: &str) -> std::io::Result<Self> {
+        let key = parse_key(&std::fs::read(path)?)?;
+        Ok(Self::new(inner, &key))
+    }
+
+    // The variable holds the key hex encoded
+    pub fn from_env(inner: S, variable: &str) -> std::io::Result<Self> {
+        let value = std::env::var(variable).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not set", variable),
+            )
+        })?;
+        let key = parse_key(value.as_bytes())?;
+        Ok(Self::new(inner, &key))
+    }
+
+    // nonce | ciphertext, authenticated against the cache key
+    fn encrypt(&self, key: &str, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: key.as_bytes(),
+        };
+        let ciphertext = self.cipher.encrypt(&nonce, payload).ok()?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Some(sealed)
+    }
+
+    fn decrypt(&self, key: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        self.cipher.decrypt(Nonce::from_slice(nonce), payload).ok()
+    }
+
+    fn seal(&self, key: &str, entry: &CacheEntry) -> Option<CacheEntry> {
+        let metadata_json = entry.metadata.to_json();
+        let mut plaintext = Vec::with_capacity(4 + metadata_json.len() + entry.body.len());
+        plaintext.extend_from_slice(&(metadata_json.len() as u32).to_be_bytes());
+        plaintext.extend_from_slice(&metadata_json);
+        plaintext.extend_from_slice(&entry.body);
+
+        let index = SealedIndex {
+            method: entry.metadata.method.clone(),
+            url: entry.metadata.url.clone(),
+            tags: entry.metadata.tags.clone(),
+            body_len: entry.body.len() as u64,
+        };
+        let index = self.encrypt(key, &serde_json::to_vec(&index).ok()?)?;
+
+        Some(CacheEntry {
+            metadata: EntryMetadata {
+                stored_at: entry.metadata.stored_at,
+                expires_at: entry.metadata.expires_at,
+                hit_count: entry.metadata.hit_count,
+                encryption: Some(Encryption::ChaCha20Poly1305),
+                sealed_index: Some(BASE64.encode(index)),
+                ..EntryMetadata::default()
+            },
+            body: self.encrypt(key, &plaintext)?,
+        })
+    }
+
+    fn open(&self, key: &str, sealed: &CacheEntry) -> Option<CacheEntry> {
+        //Plaintext entries cannot be authenticated either
+        sealed.metadata.encryption?;
+        let plaintext = self.decrypt(key, &sealed.body)?;
+
+        let (len, rest) = plaintext.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        if rest.len() < len {
+            return None;
+        }
+        let (metadata, body) = rest.split_at(len);
+        Some(CacheEntry {
+            metadata: serde_json::from_slice(metadata).ok()?,
+            body: body.to_vec(),
+        })
+    }
+}
+
+fn parse_key(bytes: &[u8]) -> std::io::Result<[u8; KEY_LEN]> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Expected a 32 byte key, raw or hex encoded",
+        )
+    };
+
+    if let Ok(key) = bytes.try_into() {
+        return Ok(key);
+    }
+    let hex = std::str::from_utf8(bytes).map_err(|_| invalid())?.trim();
+    if hex.len() != KEY_LEN * 2 {
+        return Err(invalid());
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or_else(invalid)?, 16)
+            .map_err(|_| invalid())?;
+    }
+    Ok(key)
+}
+
+#[async_trait]
+impl<S: CacheStorage> CacheStorage for EncryptedStorage<S> {
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        let sealed = self
+            .seal(key, entry)
+            .ok_or_else(|| CacheError::Serialization("Failed to encrypt entry".to_string()))?;
+        self.inner.put(key, &sealed).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let Some(sealed) = self.inner.get(key).await? else {
+            return Ok(None);
+        };
+        match self.open(key, &sealed) {
+            Some(entry) => Ok(Some(entry)),
+            None => {
+                self.inner.delete(key).await.ok(); //failed authentication
+                Err(CacheError::Corrupted(key.to_string()))
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.inner.delete(key).await
+    }
+
+    // Entries without an index part that authenticates are left out, they
+    // could not be served anyway
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        let mut body_lens = self.body_lens.lock().unwrap();
+        self.inner
+            .load_index()
+            .into_iter()
+            .filter_map(|(key, mut metadata)| {
+                let sealed = BASE64.decode(metadata.sealed_index.take()?).ok()?;
+                let index: SealedIndex =
+                    serde_json::from_slice(&self.decrypt(&key, &sealed)?).ok()?;
+                metadata.method = index.method;
+                metadata.url = index.url;
+                metadata.tags = index.tags;
+                body_lens.insert(key.clone(), index.body_len);
+                Some((key, metadata))
+            })
+            .collect()
+    }
+
+    fn body_size(&self, key: &str) -> Option<u64> {
+        self.body_lens.lock().unwrap().remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InMemoryStorage;
+
+    fn entry() -> CacheEntry {
+        CacheEntry::new_http(
+            200,
+            "OK",
+            vec![("Set-Cookie".to_string(), "session=secret".to_string())],
+            b"secret body".to_vec(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage_roundtrip() {
+        let storage = EncryptedStorage::new(InMemoryStorage::new(), &[7u8; KEY_LEN]);
+        storage.put("key", &entry()).await.unwrap();
+
+        let sealed = storage.inner.get("key").await.unwrap().unwrap();
+        assert_eq!(sealed.metadata.status, None);
+        assert!(sealed.metadata.headers.is_empty());
+        let needle = b"secret";
+        assert!(!sealed.body.windows(needle.len()).any(|w| w == needle));
+
+        assert_eq!(storage.get("key").await.unwrap(), Some(entry()));
+        assert_eq!(
+            storage.metadata("key").await.unwrap(),
+            Some(entry().metadata)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage_refuses_unauthenticated_entries() {
+        let storage = EncryptedStorage::new(InMemoryStorage::new(), &[7u8; KEY_LEN]);
+
+        storage.put("tampered", &entry()).await.unwrap();
+        let mut sealed = storage.inner.get("tampered").await.unwrap().unwrap();
+        let last = sealed.body.len() - 1;
+        sealed.body[last] ^= 0xff;
+        storage.inner.put("tampered", &sealed).await.unwrap();
+        assert!(matches!(
+            storage.get("tampered").await,
+            Err(CacheError::Corrupted(_))
+        ));
+        assert_eq!(storage.inner.get("tampered").await.unwrap(), None); //deleted
+
+        //Moved to another key
+        storage.put("a", &entry()).await.unwrap();
+        let sealed = storage.inner.get("a").await.unwrap().unwrap();
+        storage.inner.put("b", &sealed).await.unwrap();
+        assert!(storage.get("b").await.is_err());
+
+        storage.inner.put("plain", &entry()).await.unwrap();
+        assert!(storage.get("plain").await.is_err());
+
+        let other_key = EncryptedStorage::new(storage.inner, &[8u8; KEY_LEN]);
+        assert!(other_key.get("a").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage_index_survives_restart() {
+        use crate::Cache;
+        use crate::storage::SimpleFileStorage;
+
+        let path = format!("/tmp/test_encrypted_storage_index/{}", uuid::Uuid::new_v4());
+        let open = || EncryptedStorage::new(SimpleFileStorage::new(&path), &[7u8; KEY_LEN]);
+        let tagged = |url: &str, tag: &str| {
+            let mut entry = entry();
+            entry.metadata.method = Some("GET".to_string());
+            entry.metadata.url = Some(url.to_string());
+            entry.metadata.tags = vec![tag.to_string()];
+            entry
+        };
+
+        let cache = Cache::with_storage(&10, &60, open());
+        cache
+            .put("a", tagged("http://a.example/", "one"))
+            .await
+            .unwrap();
+        cache
+            .put("b", tagged("http://b.example/", "two"))
+            .await
+            .unwrap();
+        cache
+            .put("c", tagged("http://c.example/", "three"))
+            .await
+            .unwrap();
+        drop(cache);
+
+        for file in crate::storage::list_files(std::path::Path::new(&path)) {
+            let stored = String::from_utf8_lossy(&std::fs::read(file).unwrap()).into_owned();
+            assert!(!stored.contains("example") && !stored.contains("three"));
+        }
+
+        let cache = Cache::with_storage(&10, &60, open());
+        assert_eq!(cache.stats().hosts.len(), 3);
+        assert_eq!(cache.stats().total.bytes, 3 * entry().body.len() as u64);
+        assert_eq!(
+            cache
+                .invalidate_url("GET", "http://a.example/")
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(cache.invalidate_host("b.example").await.unwrap(), 1);
+        assert_eq!(cache.invalidate_tag("three").await.unwrap(), 1);
+        assert_eq!(cache.stats().total.entries, 0);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key(&[1u8; KEY_LEN]).unwrap(), [1u8; KEY_LEN]);
+        let hex = format!("{}\n", "0a".repeat(KEY_LEN));
+        assert_eq!(parse_key(hex.as_bytes()).unwrap(), [10u8; KEY_LEN]);
+        assert!(parse_key(b"too short").is_err());
+        assert!(parse_key("zz".repeat(KEY_LEN).as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+
+use super::CacheStorage;
+use crate::entry::{CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS entries (
+    key TEXT PRIMARY KEY,
+    expires_at INTEGER NOT NULL,
+    metadata TEXT NOT NULL,
+    body BLOB NOT NULL
+)";
+
+// All entries in a single SQLite database file. WAL mode lets readers run
+// alongside the single writer, so reads use their own pooled connections.
+// Blocking SQLite calls run on tokio's blocking pool.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    path: String,
+    writer: Arc<Mutex<Connection>>,
+    readers: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl SqliteStorage {
+    pub fn new(path: &str) -> Result<Self, CacheError> {
+        let writer = Connection::open(path)?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        writer.execute(SCHEMA, [])?;
+
+        Ok(SqliteStorage {
+            path: path.to_string(),
+            writer: Arc::new(Mutex::new(writer)),
+            readers: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    async fn read<R, F>(&self, query: F) -> Result<R, CacheError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<R> + Send + 'static,
+    {
+        let path = self.path.clone();
+        let readers = self.readers.clone();
+        tokio::task::spawn_blocking(move || {
+            let pooled = readers.lock().unwrap().pop();
+            let connection = match pooled {
+                Some(connection) => connection,
+                None => Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?,
+            };
+            let result = query(&connection);
+            readers.lock().unwrap().push(connection);
+            Ok(result?)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    // The number of rows changed
+    async fn write<F>(&self, statement: F) -> Result<usize, CacheError>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<usize> + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let changed = tokio::task::spawn_blocking(move || statement(&writer.lock().unwrap()))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok(changed)
+    }
+
+    // Unreadable metadata means the row is corrupted; it is dropped like a bad file
+    async fn parse_metadata(&self, key: &str, json: &str) -> Result<EntryMetadata, CacheError> {
+        match serde_json::from_str(json) {
+            Ok(metadata) => Ok(metadata),
+            Err(_) => {
+                self.delete(key).await.ok();
+                Err(CacheError::Corrupted(key.to_string()))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStorage for SqliteStorage {
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        let key = key.to_string();
+        let expires_at = i64::try_from(entry.metadata.expires_at).unwrap_or(i64::MAX);
+        let metadata = serde_json::to_string(&entry.metadata)?;
+        let body = entry.body.clone();
+        self.write(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO entries (key, expires_at, metadata, body)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![key, expires_at, metadata, body],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let owned_key = key.to_string();
+        let row = self
+            .read(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT metadata, body FROM entries WHERE key = ?1",
+                        [owned_key],
+                        |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+                    )
+                    .optional()
+            })
+            .await?;
+        let Some((metadata, body)) = row else {
+            return Ok(None);
+        };
+
+        let metadata = self.parse_metadata(key, &metadata).await?;
+        Ok(Some(CacheEntry { metadata, body }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        let key = key.to_string();
+        self.write(move |connection| {
+            connection.execute("DELETE FROM entries WHERE key = ?1", [key])
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Leaves the body column alone
+    async fn update_metadata(
+        &self,
+        key: &str,
+        metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        let key = key.to_string();
+        let expires_at = i64::try_from(metadata.expires_at).unwrap_or(i64::MAX);
+        let metadata = serde_json::to_string(&metadata)?;
+        let changed = self
+            .write(move |connection| {
+                connection.execute(
+                    "UPDATE entries SET expires_at = ?2, metadata = ?3 WHERE key = ?1",
+                    params![key, expires_at, metadata],
+                )
+            })
+            .await?;
+        Ok(changed > 0)
+    }
+
+    // Lookups only need the metadata column, never the body
+    async fn metadata(&self, key: &str) -> Result<Option<EntryMetadata>, CacheError> {
+        let owned_key = key.to_string();
+        let metadata = self
+            .read(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT metadata FROM entries WHERE key = ?1",
+                        [owned_key],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()
+            })
+            .await?;
+        match metadata {
+            Some(metadata) => Ok(Some(self.parse_metadata(key, &metadata).await?)),
+            None => Ok(None),
+        }
+    }
+
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let connection = self.writer.lock().unwrap();
+        connection
+            .execute("DELETE FROM entries WHERE expires_at <= ?1", [now])
+            .ok();
+
+        let Ok(mut statement) = connection.prepare("SELECT key, metadata FROM entries") else {
+            return Vec::new();
+        };
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        });
+        rows.map(|rows| {
+            rows.filter_map(Result::ok)
+                .filter_map(|(key, metadata)| Some((key, serde_json::from_str(&metadata).ok()?)))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    fn body_size(&self, key: &str) -> Option<u64> {
+        let connection = self.writer.lock().unwrap();
+        connection
+            .query_row(
+                "SELECT length(body) FROM entries WHERE key = ?1",
+                [key],
+                |row| row.get::<_, i64>(0),
+            )
+            .ok()
+            .and_then(|len| u64::try_from(len).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(name: &str) -> String {
+        let path = format!("/tmp/{}.sqlite", name);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{}", path, suffix)).ok();
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_put_get_delete() {
+        let storage = SqliteStorage::new(&database("test_sqlite_storage")).unwrap();
+        let entry = CacheEntry::new_http(
+            200,
+            "OK",
+            vec![("Content-Type".to_string(), "text/plain".to_string())],
+            b"value".to_vec(),
+        );
+
+        storage.put("key", &entry).await.unwrap();
+        assert_eq!(storage.get("key").await.unwrap(), Some(entry.clone()));
+        assert_eq!(
+            storage.metadata("key").await.unwrap(),
+            Some(entry.metadata.clone())
+        );
+
+        let mut metadata = entry.metadata.clone();
+        metadata.expires_at = 42;
+        assert!(
+            storage
+                .update_metadata("key", metadata.clone())
+                .await
+                .unwrap()
+        );
+        let updated = storage.get("key").await.unwrap().unwrap();
+        assert_eq!(
+            (updated.metadata, updated.body),
+            (metadata.clone(), entry.body)
+        );
+        assert!(!storage.update_metadata("other", metadata).await.unwrap());
+
+        storage.delete("key").await.unwrap();
+        assert_eq!(storage.get("key").await.unwrap(), None);
+        assert_eq!(storage.metadata("key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_load_index() {
+        let path = database("test_sqlite_storage_index");
+        let storage = SqliteStorage::new(&path).unwrap();
+
+        let mut fresh = CacheEntry::new(b"fresh".to_vec());
+        fresh.metadata.expires_at = u64::MAX;
+        storage.put("fresh", &fresh).await.unwrap();
+        storage
+            .put("expired", &CacheEntry::new(b"expired".to_vec()))
+            .await
+            .unwrap();
+        drop(storage);
+
+        let reopened = SqliteStorage::new(&path).unwrap();
+        assert_eq!(
+            reopened.load_index(),
+            vec![("fresh".to_string(), fresh.metadata)]
+        );
+        assert_eq!(reopened.get("expired").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,262 @@
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use super::CacheStorage;
+use crate::entry::{self, CHECKSUM_LEN, CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+
+pub type EntryReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type EntryWriter<'a> = Box<dyn AsyncWrite + Send + Unpin + 'a>;
+
+pub(super) type CommitFuture<'a> = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + 'a>>;
+
+// Default writer for storages without native streaming: collects the body and
+// hands the whole entry to `put` on shutdown
+pub(crate) struct BufferedEntryWriter<'a, S: CacheStorage + ?Sized> {
+    storage: &'a S,
+    key: String,
+    entry: Option<CacheEntry>,
+    commit: Option<CommitFuture<'a>>,
+}
+
+impl<'a, S: CacheStorage + ?Sized> BufferedEntryWriter<'a, S> {
+    pub fn new(storage: &'a S, key: &str, metadata: EntryMetadata) -> Self {
+        BufferedEntryWriter {
+            storage,
+            key: key.to_string(),
+            entry: Some(CacheEntry {
+                metadata,
+                body: Vec::new(),
+            }),
+            commit: None,
+        }
+    }
+}
+
+impl<S: CacheStorage + ?Sized> AsyncWrite for BufferedEntryWriter<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.entry.as_mut() {
+            Some(entry) => {
+                entry.body.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            None => Poll::Ready(Err(std::io::Error::other("Cache entry already committed"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.commit.is_none() {
+            let storage = self.storage;
+            let key = self.key.clone();
+            let entry = self.entry.take().unwrap_or_default();
+            self.commit = Some(Box::pin(async move {
+                storage
+                    .put(&key, &entry)
+                    .await
+                    .map_err(std::io::Error::from)
+            }));
+        }
+        self.commit.as_mut().unwrap().as_mut().poll(cx)
+    }
+}
+
+// Streams the body of a serialized entry file and verifies its checksum once
+// the body is exhausted. A corrupted file fails the final read and is removed.
+pub(crate) struct FileEntryReader {
+    file: tokio::io::Take<fs::File>,
+    hasher: crc32fast::Hasher,
+    expected_checksum: u32,
+    file_path: PathBuf,
+}
+
+impl FileEntryReader {
+    pub async fn open(
+        key: &str,
+        file_path: PathBuf,
+    ) -> Result<Option<(EntryMetadata, FileEntryReader)>, CacheError> {
+        let path = file_path.clone();
+        let opened = tokio::task::spawn_blocking(move || {
+            let mut file = match std::fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let file_len = file.metadata()?.len();
+
+            let parsed = entry::read_header(&mut file).and_then(|(metadata, metadata_json)| {
+                let header_len = entry::encode_header(&metadata_json).len() as u64;
+                let body_len = file_len.checked_sub(header_len + CHECKSUM_LEN as u64)?;
+
+                let mut checksum = [0u8; CHECKSUM_LEN];
+                file.seek(SeekFrom::End(-(CHECKSUM_LEN as i64))).ok()?;
+                file.read_exact(&mut checksum).ok()?;
+                file.seek(SeekFrom::Start(header_len)).ok()?;
+                Some((
+                    metadata,
+                    metadata_json,
+                    body_len,
+                    u32::from_be_bytes(checksum),
+                ))
+            });
+
+            if parsed.is_none() {
+                std::fs::remove_file(&path).ok(); //corrupted
+            }
+            Ok(Some(parsed.map(|parsed| (parsed, file))))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        let Some(opened) = opened else {
+            return Ok(None);
+        };
+        let Some(((metadata, metadata_json, body_len, expected_checksum), file)) = opened else {
+            return Err(CacheError::Corrupted(key.to_string()));
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&metadata_json);
+
+        let reader = FileEntryReader {
+            file: fs::File::from_std(file).take(body_len),
+            hasher,
+            expected_checksum,
+            file_path,
+        };
+        Ok(Some((metadata, reader)))
+    }
+}
+
+impl AsyncRead for FileEntryReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut self.file).poll_read(cx, buf))?;
+        let read = &buf.filled()[filled_before..];
+
+        if !read.is_empty() {
+            self.hasher.update(read);
+            return Poll::Ready(Ok(()));
+        }
+
+        //End of body
+        if self.hasher.clone().finalize() != self.expected_checksum {
+            std::fs::remove_file(&self.file_path).ok();
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Cache entry failed its checksum",
+            )));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Streams a body into a temp file; shutdown appends the checksum, syncs and
+// renames it into place. Dropping it before that discards the temp file.
+pub(crate) struct FileEntryWriter {
+    file: Option<fs::File>,
+    hasher: crc32fast::Hasher,
+    temp_path: PathBuf,
+    file_path: PathBuf,
+    commit: Option<CommitFuture<'static>>,
+    committed: bool,
+}
+
+impl FileEntryWriter {
+    pub async fn create(
+        temp_path: PathBuf,
+        file_path: PathBuf,
+        metadata: &EntryMetadata,
+    ) -> std::io::Result<FileEntryWriter> {
+        let metadata_json = metadata.to_json();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&metadata_json);
+
+        let mut writer = FileEntryWriter {
+            file: None,
+            hasher,
+            temp_path,
+            file_path,
+            commit: None,
+            committed: false,
+        };
+        //Created through `writer` so a failing header write still cleans up
+        let file = writer
+            .file
+            .insert(fs::File::create(&writer.temp_path).await?);
+        file.write_all(&entry::encode_header(&metadata_json))
+            .await?;
+        Ok(writer)
+    }
+}
+
+impl AsyncWrite for FileEntryWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let Some(file) = self.file.as_mut() else {
+            return Poll::Ready(Err(std::io::Error::other("Cache entry already committed")));
+        };
+        let written = ready!(Pin::new(file).poll_write(cx, buf))?;
+        self.hasher.update(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.file.as_mut() {
+            Some(file) => Pin::new(file).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.commit.is_none() {
+            let Some(mut file) = self.file.take() else {
+                return Poll::Ready(Err(std::io::Error::other("Cache entry already committed")));
+            };
+            let checksum = self.hasher.clone().finalize();
+            let temp_path = self.temp_path.clone();
+            let file_path = self.file_path.clone();
+            self.commit = Some(Box::pin(async move {
+                file.write_all(&checksum.to_be_bytes()).await?;
+                file.flush().await?; //tokio only finishes the write on flush
+                file.sync_all().await?;
+                fs::rename(&temp_path, &file_path).await
+            }));
+        }
+
+        let result = ready!(self.commit.as_mut().unwrap().as_mut().poll(cx));
+        self.committed = result.is_ok();
+        Poll::Ready(result)
+    }
+}
+
+impl Drop for FileEntryWriter {
+    fn drop(&mut self) {
+        if !self.committed {
+            std::fs::remove_file(&self.temp_path).ok();
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, ready};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::stream::CommitFuture;
+use super::{CacheStorage, EntryReader, EntryWriter, InMemoryStorage, SimpleFileStorage};
+use crate::entry::{self, CHECKSUM_LEN, CacheEntry, EntryMetadata};
+use crate::error::CacheError;
+
+// Serialized size and recency of the entries held by one tier
+#[derive(Debug, Default)]
+struct TierUsage {
+    //key -> (size, last use)
+    entries: HashMap<String, (u64, u64)>,
+    //last use -> key, oldest first
+    order: BTreeMap<u64, String>,
+    bytes: u64,
+    clock: u64,
+}
+
+impl TierUsage {
+    fn record(&mut self, key: &str, size: u64) {
+        self.remove(key);
+        self.clock += 1;
+        self.entries.insert(key.to_string(), (size, self.clock));
+        self.order.insert(self.clock, key.to_string());
+        self.bytes += size;
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(size) = self.size(key) {
+            self.record(key, size);
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<u64> {
+        let (size, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+        self.bytes -= size;
+        Some(size)
+    }
+
+    fn size(&self, key: &str) -> Option<u64> {
+        self.entries.get(key).map(|(size, _)| *size)
+    }
+
+    // Least recently used keys that have to go to get back under `limit`
+    fn evict_over(&mut self, limit: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.bytes > limit {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.entries.remove(&key) {
+                self.bytes -= size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+// Small, hot entries in memory in front of a large, cold set on disk. Each
+// entry lives in exactly one tier: disk hits are promoted into memory and
+// whatever memory evicts to stay under its limit is demoted to disk. Entries
+// evicted from disk are gone. Moves write the destination tier before deleting
+// from the source, and reads look at memory again after missing on disk, so an
+// entry moving between tiers is never reported missing.
+#[derive(Debug)]
+pub struct TieredStorage {
+    memory: InMemoryStorage,
+    disk: SimpleFileStorage,
+    memory_limit_bytes: u64,
+    disk_limit_bytes: u64,
+    memory_usage: Mutex<TierUsage>,
+    disk_usage: Mutex<TierUsage>,
+}
+
+impl TieredStorage {
+    pub fn new(path: &str, memory_limit_bytes: u64, disk_limit_bytes: u64) -> Self {
+        TieredStorage {
+            memory: InMemoryStorage::new(),
+            disk: SimpleFileStorage::new(path),
+            memory_limit_bytes,
+            disk_limit_bytes,
+            memory_usage: Mutex::new(TierUsage::default()),
+            disk_usage: Mutex::new(TierUsage::default()),
+        }
+    }
+
+    pub fn memory_bytes(&self) -> u64 {
+        self.memory_usage.lock().unwrap().bytes
+    }
+
+    pub fn disk_bytes(&self) -> u64 {
+        self.disk_usage.lock().unwrap().bytes
+    }
+
+    fn in_memory(&self, key: &str) -> bool {
+        self.memory_usage.lock().unwrap().size(key).is_some()
+    }
+
+    async fn put_memory(&self, key: &str, entry: &CacheEntry, size: u64) -> Result<(), CacheError> {
+        self.memory.put(key, entry).await?;
+        self.memory_usage.lock().unwrap().record(key, size);
+        if self.disk_usage.lock().unwrap().remove(key).is_some() {
+            self.disk.delete(key).await.ok();
+        }
+
+        let demoted = self
+            .memory_usage
+            .lock()
+            .unwrap()
+            .evict_over(self.memory_limit_bytes);
+        for key in demoted {
+            if let Ok(Some(entry)) = self.memory.get(&key).await {
+                self.put_disk(&key, &entry).await.ok();
+                if !self.in_memory(&key) {
+                    self.memory.delete(&key).await.ok(); //unless put back meanwhile
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn put_disk(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        self.disk.put(key, entry).await?;
+        self.disk_written(key, entry.to_bytes().len() as u64).await;
+        Ok(())
+    }
+
+    async fn get_memory(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        let entry = self.memory.get(key).await;
+        match &entry {
+            Ok(Some(_)) => self.memory_usage.lock().unwrap().touch(key),
+            Ok(None) => {} //not here, or being demoted
+            Err(_) => {
+                self.memory_usage.lock().unwrap().remove(key);
+            }
+        }
+        entry
+    }
+
+    async fn disk_written(&self, key: &str, size: u64) {
+        if self.memory_usage.lock().unwrap().remove(key).is_some() {
+            self.memory.delete(key).await.ok();
+        }
+        self.disk_usage.lock().unwrap().record(key, size);
+
+        let evicted = self
+            .disk_usage
+            .lock()
+            .unwrap()
+            .evict_over(self.disk_limit_bytes);
+        for key in evicted {
+            self.disk.delete(&key).await.ok();
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStorage for TieredStorage {
+    async fn put(&self, key: &str, entry: &CacheEntry) -> Result<(), CacheError> {
+        let size = entry.to_bytes().len() as u64;
+        if size <= self.memory_limit_bytes {
+            self.put_memory(key, entry, size).await
+        } else {
+            self.put_disk(key, entry).await //would not fit in memory anyway
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<CacheEntry>, CacheError> {
+        if let Some(entry) = self.get_memory(key).await? {
+            return Ok(Some(entry));
+        }
+
+        let entry = self.disk.get(key).await;
+        let size = self.disk_usage.lock().unwrap().size(key);
+        match (&entry, size) {
+            (Ok(Some(entry)), Some(size)) if size <= self.memory_limit_bytes => {
+                self.put_memory(key, entry, size).await.ok(); //promote
+            }
+            (Ok(Some(_)), _) => self.disk_usage.lock().unwrap().touch(key),
+            (Ok(None), _) => {
+                //promoted by a concurrent get since we looked at memory
+                if let Some(entry) = self.get_memory(key).await? {
+                    return Ok(Some(entry));
+                }
+                self.disk_usage.lock().unwrap().remove(key);
+            }
+            (Err(CacheError::Corrupted(_)), _) => {
+                self.disk_usage.lock().unwrap().remove(key);
+            }
+            (Err(_), _) => {}
+        }
+        entry
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        let in_memory = self.memory_usage.lock().unwrap().remove(key).is_some();
+        let on_disk = self.disk_usage.lock().unwrap().remove(key).is_some();
+        if in_memory {
+            self.memory.delete(key).await?;
+        }
+        if on_disk {
+            self.disk.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    // Entries small enough for memory are promoted through `get`, larger ones
+    // stream straight from disk
+    async fn open_read(
+        &self,
+        key: &str,
+    ) -> Result<Option<(EntryMetadata, EntryReader)>, CacheError> {
+        let disk_size = self.disk_usage.lock().unwrap().size(key);
+        if self.in_memory(key) || disk_size.is_some_and(|size| size <= self.memory_limit_bytes) {
+            return Ok(self.get(key).await?.map(|entry| {
+                let reader: EntryReader = Box::new(std::io::Cursor::new(entry.body));
+                (entry.metadata, reader)
+            }));
+        }
+
+        let opened = self.disk.open_read(key).await;
+        match &opened {
+            Ok(Some(_)) => self.disk_usage.lock().unwrap().touch(key),
+            Ok(None) | Err(CacheError::Corrupted(_)) => {
+                self.disk_usage.lock().unwrap().remove(key);
+            }
+            Err(_) => {}
+        }
+        opened
+    }
+
+    // Bodies of unknown size stream to disk; a later hit promotes them if they fit
+    async fn open_write<'a>(
+        &'a self,
+        key: &str,
+        metadata: EntryMetadata,
+    ) -> Result<EntryWriter<'a>, CacheError> {
+        let header_len = entry::encode_header(&metadata.to_json()).len() + CHECKSUM_LEN;
+        let inner = self.disk.open_write(key, metadata).await?;
+        Ok(Box::new(TieredEntryWriter {
+            storage: self,
+            key: key.to_string(),
+            inner: Some(inner),
+            written: header_len as u64,
+            commit: None,
+        }))
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<EntryMetadata>, CacheError> {
+        if let Some(metadata) = self.memory.metadata(key).await? {
+            return Ok(Some(metadata));
+        }
+        match self.disk.metadata(key).await? {
+            Some(metadata) => Ok(Some(metadata)),
+            None => self.memory.metadata(key).await,
+        }
+    }
+
+    // Disk entries are updated in place where they can be, see `SimpleFileStorage`
+    async fn update_metadata(
+        &self,
+        key: &str,
+        metadata: EntryMetadata,
+    ) -> Result<bool, CacheError> {
+        if !self.in_memory(key) && self.disk.update_metadata(key, metadata.clone()).await? {
+            return Ok(true);
+        }
+        let Some(mut entry) = self.get_memory(key).await? else {
+            return Ok(false);
+        };
+        entry.metadata = metadata;
+        self.put(key, &entry).await?;
+        Ok(true)
+    }
+
+    // Memory starts out empty, so the index is whatever survived on disk
+    fn load_index(&self) -> Vec<(String, EntryMetadata)> {
+        let mut index = self.disk.load_index();
+        let mut usage = self.disk_usage.lock().unwrap();
+        for (key, _) in &index {
+            let size = self
+                .disk
+                .file_path(key)
+                .ok()
+                .and_then(|file_path| std::fs::metadata(file_path).ok())
+                .map_or(0, |metadata| metadata.len());
+            usage.record(key, size);
+        }
+
+        let evicted = usage.evict_over(self.disk_limit_bytes);
+        for key in &evicted {
+            if let Ok(file_path) = self.disk.file_path(key) {
+                std::fs::remove_file(file_path).ok();
+            }
+        }
+        index.retain(|(key, _)| !evicted.contains(key));
+        index
+    }
+
+    fn body_size(&self, key: &str) -> Option<u64> {
+        self.disk.body_size(key)
+    }
+}
+
+// Disk writer that accounts the entry against the disk limit once committed
+struct TieredEntryWriter<'a> {
+    storage: &'a TieredStorage,
+    key: String,
+    inner: Option<EntryWriter<'a>>,
+    written: u64,
+    commit: Option<CommitFuture<'a>>,
+}
+
+impl AsyncWrite for TieredEntryWriter<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let Some(inner) = self.inner.as_mut() else {
+            return Poll::Ready(Err(std::io::Error::other("Cache entry already committed")));
+        };
+        let written = ready!(Pin::new(inner).poll_write(cx, buf))?;
+        self.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.inner.as_mut() {
+            Some(inner) => Pin::new(inner).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.commit.is_none() {
+            let Some(mut inner) = self.inner.take() else {
+                return Poll::Ready(Err(std::io::Error::other("Cache entry already committed")));
+            };
+            let storage = self.storage;
+            let key = self.key.clone();
+            let size = self.written;
+            self.commit = Some(Box::pin(async move {
+                inner.shutdown().await?;
+                storage.disk_written(&key, size).await;
+                Ok(())
+            }));
+        }
+        self.commit.as_mut().unwrap().as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(body: &[u8]) -> CacheEntry {
+        let mut entry = CacheEntry::new(body.to_vec());
+        entry.metadata.expires_at = u64::MAX; //survives `load_index`
+        entry
+    }
+
+    fn size_of(body: &[u8]) -> u64 {
+        entry(body).to_bytes().len() as u64
+    }
+
+    #[tokio::test]
+    async fn test_tiered_storage_demotes_and_promotes() {
+        let path = format!("/tmp/test_tiered_storage_tiers/{}", uuid::Uuid::new_v4());
+        //Room for exactly one entry in memory
+        let storage = TieredStorage::new(&path, size_of(b"aaaa"), 1024);
+
+        storage.put("a", &entry(b"aaaa")).await.unwrap();
+        assert!(storage.in_memory("a"));
+
+        storage.put("b", &entry(b"bbbb")).await.unwrap();
+        assert!(storage.in_memory("b"));
+        assert!(!storage.in_memory("a")); //demoted
+        assert_eq!(storage.disk.get("a").await.unwrap(), Some(entry(b"aaaa")));
+
+        assert_eq!(storage.get("a").await.unwrap(), Some(entry(b"aaaa")));
+        assert!(storage.in_memory("a")); //promoted
+        assert_eq!(storage.disk.get("a").await.unwrap(), None);
+        assert_eq!(storage.memory.get("b").await.unwrap(), None);
+        assert_eq!(storage.get("b").await.unwrap(), Some(entry(b"bbbb")));
+
+        assert_eq!(storage.memory_bytes(), size_of(b"aaaa"));
+        assert_eq!(storage.disk_bytes(), size_of(b"aaaa"));
+    }
+
+    #[tokio::test]
+    async fn test_tiered_storage_disk_limit() {
+        let path = format!(
+            "/tmp/test_tiered_storage_disk_limit/{}",
+            uuid::Uuid::new_v4()
+        );
+        let storage = TieredStorage::new(&path, 0, 2 * size_of(b"large"));
+
+        for key in ["a", "b", "c"] {
+            storage.put(key, &entry(b"large")).await.unwrap();
+        }
+        assert_eq!(storage.memory_bytes(), 0);
+        assert_eq!(storage.disk_bytes(), 2 * size_of(b"large"));
+        assert_eq!(storage.get("a").await.unwrap(), None); //oldest evicted
+        assert_eq!(storage.get("c").await.unwrap(), Some(entry(b"large")));
+
+        let reopened = TieredStorage::new(&path, 0, size_of(b"large"));
+        assert_eq!(reopened.load_index().len(), 1);
+        assert_eq!(reopened.disk_bytes(), size_of(b"large"));
+    }
+
+    #[tokio::test]
+    async fn test_tiered_storage_streaming_write() {
+        let path = format!(
+            "/tmp/test_tiered_storage_streaming/{}",
+            uuid::Uuid::new_v4()
+        );
+        let storage = TieredStorage::new(&path, 1024, 1024);
+
+        let mut writer = storage
+            .open_write("key", entry(b"").metadata)
+            .await
+            .unwrap();
+        writer.write_all(b"body").await.unwrap();
+        writer.shutdown().await.unwrap();
+        drop(writer);
+
+        assert_eq!(storage.disk_bytes(), size_of(b"body"));
+        assert_eq!(storage.get("key").await.unwrap(), Some(entry(b"body")));
+        assert!(storage.in_memory("key"));
+        assert_eq!(storage.disk_bytes(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_tiered_storage_get_during_promotion() {
+        let path = format!(
+            "/tmp/test_tiered_storage_concurrent/{}",
+            uuid::Uuid::new_v4()
+        );
+        //Every hit on one key promotes it and demotes the other
+        let storage = std::sync::Arc::new(TieredStorage::new(&path, size_of(b"aaaa"), 1024));
+        storage.put("a", &entry(b"aaaa")).await.unwrap();
+        storage.put("b", &entry(b"bbbb")).await.unwrap();
+
+        let readers: Vec<_> = (0..8)
+            .map(|reader| {
+                let storage = storage.clone();
+                let key = if reader % 2 == 0 { "a" } else { "b" };
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        assert!(
+                            storage.get(key).await.unwrap().is_some(),
+                            "{} reported missing while moving between tiers",
+                            key
+                        );
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    }
+}
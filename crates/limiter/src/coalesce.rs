@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::watch;
+
+// Requests may only join while the whole response so far is still buffered.
+// Past that the buffer only keeps what the slowest follower has not sent yet.
+const JOIN_LIMIT: usize = 1024 * 1024;
+// Followers lagging further behind than this are detached from the fetch
+const BUFFER_LIMIT: usize = 4 * JOIN_LIMIT;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Progress {
+    Streaming(usize),
+    Done(usize),
+    Failed,
+}
+
+// Window of the response still needed by someone; `base` is the offset of its first byte
+#[derive(Default)]
+struct Buffer {
+    base: usize,
+    bytes: Vec<u8>,
+    //follower id -> bytes already sent
+    followers: HashMap<u64, usize>,
+    detached: HashSet<u64>,
+    next_follower: u64,
+}
+
+impl Buffer {
+    fn len(&self) -> usize {
+        self.base + self.bytes.len()
+    }
+
+    fn joinable(&self) -> bool {
+        self.len() <= JOIN_LIMIT
+    }
+
+    fn trim(&mut self) {
+        if self.joinable() {
+            return;
+        }
+        let oldest = self.len().saturating_sub(BUFFER_LIMIT);
+        let lagging: Vec<u64> = self
+            .followers
+            .iter()
+            .filter(|(_, sent)| **sent < oldest)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in lagging {
+            self.followers.remove(&id);
+            self.detached.insert(id);
+        }
+        let sent = self.followers.values().min().copied().unwrap_or(self.len());
+        self.bytes.drain(..sent - self.base);
+        self.base = sent;
+    }
+}
+
+// Response of one upstream fetch, shared with every request waiting on the same key
+pub(crate) struct InFlight {
+    buffer: Mutex<Buffer>,
+    progress: watch::Sender<Progress>,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        InFlight {
+            buffer: Mutex::new(Buffer::default()),
+            progress: watch::Sender::new(Progress::Streaming(0)),
+        }
+    }
+}
+
+pub(crate) struct Follower {
+    in_flight: Arc<InFlight>,
+    id: u64,
+}
+
+impl Follower {
+    // Streams the shared response into `writer` as bytes arrive. Returns `false`
+    // if the leading fetch failed or left us behind before anything was written,
+    // so the caller can fetch on its own instead.
+    pub async fn stream_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<bool> {
+        let mut progress = self.in_flight.progress.subscribe();
+        let mut sent = 0;
+
+        loop {
+            let state = *progress.borrow_and_update();
+            let available = match state {
+                Progress::Streaming(len) | Progress::Done(len) => len,
+                Progress::Failed if sent == 0 => return Ok(false),
+                Progress::Failed => {
+                    return Err(std::io::Error::other("Coalesced upstream fetch failed"));
+                }
+            };
+
+            if available > sent {
+                let chunk = {
+                    let buffer = self.in_flight.buffer.lock().unwrap();
+                    (!buffer.detached.contains(&self.id))
+                        .then(|| buffer.bytes[sent - buffer.base..available - buffer.base].to_vec())
+                };
+                let Some(chunk) = chunk else {
+                    if sent == 0 {
+                        return Ok(false);
+                    }
+                    return Err(std::io::Error::other(
+                        "Fell too far behind the coalesced upstream fetch",
+                    ));
+                };
+                writer.write_all(&chunk).await?;
+                sent = available;
+
+                let mut buffer = self.in_flight.buffer.lock().unwrap();
+                if let Some(progress) = buffer.followers.get_mut(&self.id) {
+                    *progress = sent;
+                }
+                buffer.trim();
+            }
+
+            if matches!(state, Progress::Done(_)) {
+                return Ok(true);
+            }
+            if progress.changed().await.is_err() {
+                return Err(std::io::Error::other("Coalesced upstream fetch vanished"));
+            }
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        let mut buffer = self.in_flight.buffer.lock().unwrap();
+        buffer.followers.remove(&self.id);
+        buffer.detached.remove(&self.id);
+        buffer.trim();
+    }
+}
+
+pub(crate) enum Role<'a> {
+    Leader(Leader<'a>),
+    Follower(Follower),
+    //A fetch for the key is too far along to join, fetch independently
+    Bypass,
+}
+
+// Fetches currently in progress, keyed by cache key
+#[derive(Default)]
+pub(crate) struct InFlightRequests {
+    requests: DashMap<String, Arc<InFlight>>,
+}
+
+impl InFlightRequests {
+    pub fn join(&self, key: &str) -> Role<'_> {
+        match self.requests.entry(key.to_string()) {
+            Entry::Occupied(entry) => {
+                let in_flight = entry.get().clone();
+                let mut buffer = in_flight.buffer.lock().unwrap();
+                if !buffer.joinable() {
+                    return Role::Bypass;
+                }
+                let id = buffer.next_follower;
+                buffer.next_follower += 1;
+                buffer.followers.insert(id, 0);
+                drop(buffer);
+                Role::Follower(Follower { in_flight, id })
+            }
+            Entry::Vacant(entry) => {
+                let in_flight = Arc::new(InFlight::new());
+                entry.insert(in_flight.clone());
+                Role::Leader(Leader {
+                    requests: &self.requests,
+                    key: key.to_string(),
+                    in_flight,
+                    finished: false,
+                })
+            }
+        }
+    }
+}
+
+// Handle of the request doing the actual fetch; dropping it unfinished fails the followers
+pub(crate) struct Leader<'a> {
+    requests: &'a DashMap<String, Arc<InFlight>>,
+    key: String,
+    in_flight: Arc<InFlight>,
+    finished: bool,
+}
+
+impl Leader<'_> {
+    pub fn push(&self, bytes: &[u8]) {
+        let len = {
+            let mut buffer = self.in_flight.buffer.lock().unwrap();
+            buffer.bytes.extend_from_slice(bytes);
+            buffer.trim();
+            buffer.len()
+        };
+        self.in_flight
+            .progress
+            .send_replace(Progress::Streaming(len));
+    }
+
+    pub fn finish(mut self) {
+        let len = self.in_flight.buffer.lock().unwrap().len();
+        self.in_flight.progress.send_replace(Progress::Done(len));
+        self.finished = true;
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.in_flight.progress.send_replace(Progress::Failed);
+        }
+        self.requests.remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_followers_receive_leader_bytes() {
+        let requests = InFlightRequests::default();
+        let Role::Leader(leader) = requests.join("key") else {
+            panic!("First request should lead");
+        };
+        let Role::Follower(follower) = requests.join("key") else {
+            panic!("Second request should follow");
+        };
+
+        let follower_task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let completed = follower.stream_to(&mut received).await.unwrap();
+            (completed, received)
+        });
+
+        leader.push(b"Hello ");
+        tokio::task::yield_now().await;
+        leader.push(b"World!");
+        leader.finish();
+
+        assert_eq!(
+            follower_task.await.unwrap(),
+            (true, b"Hello World!".to_vec())
+        );
+        assert!(matches!(requests.join("key"), Role::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn test_followers_fall_back_when_leader_fails() {
+        let requests = InFlightRequests::default();
+        let leader = requests.join("key");
+        let Role::Follower(follower) = requests.join("key") else {
+            panic!("Second request should follow");
+        };
+
+        drop(leader);
+
+        let mut received = Vec::new();
+        assert!(!follower.stream_to(&mut received).await.unwrap());
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_is_bounded_past_join_limit() {
+        let requests = InFlightRequests::default();
+        let Role::Leader(leader) = requests.join("key") else {
+            panic!("First request should lead");
+        };
+        let Role::Follower(follower) = requests.join("key") else {
+            panic!("Second request should follow");
+        };
+
+        let chunk = vec![b'x'; JOIN_LIMIT];
+        leader.push(&chunk);
+        leader.push(&chunk);
+        assert!(matches!(requests.join("key"), Role::Bypass));
+        //Nothing sent yet, so everything is still held for the follower
+        assert_eq!(
+            leader.in_flight.buffer.lock().unwrap().bytes.len(),
+            2 * JOIN_LIMIT
+        );
+
+        let mut received = Vec::new();
+        leader.finish();
+        assert!(follower.stream_to(&mut received).await.unwrap());
+        assert_eq!(received.len(), 2 * JOIN_LIMIT);
+
+        let buffer = follower.in_flight.buffer.lock().unwrap();
+        assert!(buffer.bytes.is_empty());
+        assert_eq!(buffer.base, 2 * JOIN_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_followers_are_detached() {
+        let requests = InFlightRequests::default();
+        let Role::Leader(leader) = requests.join("key") else {
+            panic!("First request should lead");
+        };
+        let Role::Follower(idle) = requests.join("key") else {
+            panic!("Second request should follow");
+        };
+        let Role::Follower(started) = requests.join("key") else {
+            panic!("Third request should follow");
+        };
+
+        leader.push(b"Hello");
+        let started_task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            started
+                .stream_to(&mut received)
+                .await
+                .map_err(|e| e.to_string())
+        });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        let chunk = vec![b'x'; JOIN_LIMIT];
+        for _ in 0..5 {
+            leader.push(&chunk);
+        }
+        {
+            let buffer = leader.in_flight.buffer.lock().unwrap();
+            assert!(buffer.followers.is_empty());
+            assert!(buffer.bytes.is_empty());
+        }
+
+        //Nothing sent yet, so it can still fetch on its own
+        let mut received = Vec::new();
+        assert!(!idle.stream_to(&mut received).await.unwrap());
+        assert!(received.is_empty());
+        let error = started_task.await.unwrap().unwrap_err();
+        assert!(error.contains("behind"));
+        leader.finish();
+    }
+}
@@ -0,0 +1,310 @@
+use std::time::Duration;
+
+use cache::EntryMetadata;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// How this proxy names itself in `Via`
+const VIA: &str = concat!("1.1 ", env!("CARGO_PKG_NAME"));
+
+// Minimal view on an HTTP/1.x response head, enough for cache decisions
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    pub fn parse(bytes: &[u8]) -> Option<ResponseHead> {
+        let head_len = find_head_end(bytes)?;
+        let head = std::str::from_utf8(&bytes[..head_len]).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut status_line = lines.next()?.splitn(3, ' ');
+        let version = status_line.next()?.to_string();
+        let status = status_line.next()?.parse().ok()?;
+        let reason = status_line.next().unwrap_or("").to_string();
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Some(ResponseHead {
+            version,
+            status,
+            reason,
+            headers,
+        })
+    }
+
+    pub fn to_metadata(&self) -> EntryMetadata {
+        EntryMetadata {
+            status: Some(self.status),
+            reason: self.reason.clone(),
+            headers: self.headers.clone(),
+            tags: self.tags(),
+            ..EntryMetadata::default()
+        }
+    }
+
+    // Purge tags announced upstream via `Cache-Tag` (comma separated) or
+    // `Surrogate-Key` (space separated)
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = Vec::new();
+        for (name, value) in &self.headers {
+            let split: Vec<&str> = if name.eq_ignore_ascii_case("Cache-Tag") {
+                value.split(',').collect()
+            } else if name.eq_ignore_ascii_case("Surrogate-Key") {
+                value.split_whitespace().collect()
+            } else {
+                continue;
+            };
+            for tag in split
+                .into_iter()
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+            {
+                if !tags.iter().any(|t| t == tag) {
+                    tags.push(tag.to_string());
+                }
+            }
+        }
+        tags
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// Index right after the `\r\n\r\n` terminating the head, if complete
+pub(crate) fn find_head_end(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+// Reads from `reader` into `buffer` until a full head is buffered or EOF is hit.
+// Bytes read past the head stay in `buffer`.
+pub(crate) async fn read_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+) -> std::io::Result<Option<usize>> {
+    let mut chunk = [0u8; 8192];
+    loop {
+        if let Some(head_len) = find_head_end(buffer) {
+            return Ok(Some(head_len));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+// Value of a header within raw request header lines (as read from the client)
+pub(crate) fn request_header<'a>(lines: &'a [String], name: &str) -> Option<&'a str> {
+    lines.iter().find_map(|line| {
+        let (n, v) = line.split_once(':')?;
+        n.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+// Builds `If-None-Match`/`If-Modified-Since` lines from a stored response
+pub(crate) fn conditional_headers(stored: &EntryMetadata) -> Option<String> {
+    let mut headers = String::new();
+    if let Some(etag) = stored.header("ETag") {
+        headers.push_str(&format!("If-None-Match: {}\r\n", etag));
+    }
+    if let Some(last_modified) = stored.header("Last-Modified") {
+        headers.push_str(&format!("If-Modified-Since: {}\r\n", last_modified));
+    }
+    (!headers.is_empty()).then_some(headers)
+}
+
+// Response head to send in front of a stored body. Entries that are not a
+// parsed HTTP response (e.g. CONNECT tunnels) are sent as their raw body.
+pub(crate) fn response_head(metadata: &EntryMetadata) -> Vec<u8> {
+    let Some(status) = metadata.status else {
+        return Vec::new();
+    };
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, metadata.reason);
+    for (name, value) in &metadata.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head.into_bytes()
+}
+
+// Adds a header line right after the status line of a raw response
+pub(crate) fn insert_header(response: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let Some(status_line_end) = response.windows(2).position(|w| w == b"\r\n") else {
+        return response.to_vec();
+    };
+    let split = status_line_end + 2;
+
+    let mut with_header = Vec::with_capacity(response.len() + name.len() + value.len() + 4);
+    with_header.extend_from_slice(&response[..split]);
+    with_header.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    with_header.extend_from_slice(&response[split..]);
+    with_header
+}
+
+// Tells clients where a response came from: `x_cache` is `HIT`, `MISS` or
+// `STALE`, `waited` the time the request spent in the throttler. An upstream
+// `X-Cache` is replaced, and our `Via` follows the upstream's since hops are
+// listed in the order they were passed (RFC 9110, section 7.6.3).
+pub(crate) fn insert_cache_headers(response: &[u8], x_cache: &str, waited: Duration) -> Vec<u8> {
+    let Some(head_end) = find_head_end(response) else {
+        return response.to_vec();
+    };
+    let mut lines = response[..head_end - 2].split_inclusive(|&byte| byte == b'\n');
+    let Some(status_line) = lines.next() else {
+        return response.to_vec();
+    };
+    let named = |line: &[u8], name: &str| {
+        line.split(|&byte| byte == b':')
+            .next()
+            .is_some_and(|n| n.trim_ascii().eq_ignore_ascii_case(name.as_bytes()))
+    };
+    let headers: Vec<&[u8]> = lines.filter(|line| !named(line, "X-Cache")).collect();
+    let last_via = headers.iter().rposition(|line| named(line, "Via"));
+    let via = format!("Via: {}\r\n", VIA);
+
+    let mut annotated = Vec::with_capacity(response.len() + 128);
+    annotated.extend_from_slice(status_line);
+    annotated.extend_from_slice(format!("X-Cache: {}\r\n", x_cache).as_bytes());
+    if last_via.is_none() {
+        annotated.extend_from_slice(via.as_bytes());
+    }
+    annotated
+        .extend_from_slice(format!("X-Limiter-Wait-Ms: {}\r\n", waited.as_millis()).as_bytes());
+    for (index, line) in headers.iter().enumerate() {
+        annotated.extend_from_slice(line);
+        if last_via == Some(index) {
+            annotated.extend_from_slice(via.as_bytes());
+        }
+    }
+    annotated.extend_from_slice(&response[head_end - 2..]);
+    annotated
+}
+
+// Ages a stored response by the time it spent in the cache, on top of any
+// `Age` it already had when it was stored
+pub(crate) fn set_age(metadata: &mut EntryMetadata, now: u64) {
+    let stored_age: u64 = metadata
+        .header("Age")
+        .and_then(|age| age.parse().ok())
+        .unwrap_or(0);
+    let age = stored_age + now.saturating_sub(metadata.stored_at);
+    metadata
+        .headers
+        .retain(|(name, _)| !name.eq_ignore_ascii_case("Age"));
+    metadata.headers.push(("Age".to_string(), age.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response_head() {
+        let response = b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 2\r\n\r\nhi";
+        let head = ResponseHead::parse(response).unwrap();
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.status, 200);
+        assert_eq!(head.reason, "OK");
+        assert_eq!(head.header("etag"), Some("\"v1\""));
+        assert_eq!(find_head_end(response), Some(response.len() - 2));
+    }
+
+    #[test]
+    fn test_conditional_headers() {
+        let response = b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n";
+        let metadata = ResponseHead::parse(response).unwrap().to_metadata();
+        assert_eq!(
+            conditional_headers(&metadata).unwrap(),
+            "If-None-Match: \"v1\"\r\nIf-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT\r\n"
+        );
+        assert_eq!(conditional_headers(&EntryMetadata::default()), None);
+    }
+
+    #[test]
+    fn test_response_tags() {
+        let response = b"HTTP/1.1 200 OK\r\nCache-Tag: a, b\r\nSurrogate-Key: b  c\r\n\r\n";
+        let head = ResponseHead::parse(response).unwrap();
+        assert_eq!(head.to_metadata().tags, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_response_head_roundtrip() {
+        let head =
+            b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 4\r\n\r\n";
+        let metadata = ResponseHead::parse(head).unwrap().to_metadata();
+        assert_eq!(metadata.status, Some(404));
+        assert_eq!(metadata.header("content-type"), Some("text/plain"));
+        assert_eq!(response_head(&metadata), head);
+        assert_eq!(response_head(&EntryMetadata::default()), b"");
+    }
+
+    #[test]
+    fn test_insert_header() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi";
+        assert_eq!(
+            insert_header(response, "Warning", "110 - \"Response is Stale\""),
+            b"HTTP/1.1 200 OK\r\nWarning: 110 - \"Response is Stale\"\r\nContent-Length: 2\r\n\r\nhi"
+        );
+        assert_eq!(insert_header(b"garbage", "Warning", "x"), b"garbage");
+    }
+
+    #[test]
+    fn test_insert_cache_headers() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nX-Cache: HIT\r\nVia: 1.0 cdn\r\nVia: 1.1 edge\r\nETag: \"v1\"\r\n\r\nbody";
+        let annotated = insert_cache_headers(response, "MISS", Duration::from_millis(1500));
+        assert_eq!(
+            String::from_utf8(annotated).unwrap(),
+            format!(
+                "HTTP/1.1 200 OK\r\nX-Cache: MISS\r\nX-Limiter-Wait-Ms: 1500\r\nVia: 1.0 cdn\r\nVia: 1.1 edge\r\nVia: {}\r\nETag: \"v1\"\r\n\r\nbody",
+                VIA
+            )
+        );
+
+        let annotated =
+            insert_cache_headers(b"HTTP/1.1 204 No Content\r\n\r\n", "HIT", Duration::ZERO);
+        assert_eq!(
+            String::from_utf8(annotated).unwrap(),
+            format!(
+                "HTTP/1.1 204 No Content\r\nX-Cache: HIT\r\nVia: {}\r\nX-Limiter-Wait-Ms: 0\r\n\r\n",
+                VIA
+            )
+        );
+        assert_eq!(
+            insert_cache_headers(b"garbage", "HIT", Duration::ZERO),
+            b"garbage"
+        );
+    }
+
+    #[test]
+    fn test_set_age() {
+        let mut metadata = EntryMetadata {
+            status: Some(200),
+            headers: vec![("age".to_string(), "5".to_string())],
+            stored_at: 100,
+            ..EntryMetadata::default()
+        };
+        set_age(&mut metadata, 130);
+        assert_eq!(
+            metadata.headers,
+            vec![("Age".to_string(), "35".to_string())]
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+
+use sha2::{Digest, Sha256};
+use url::Url;
+
+// Keyed whatever the rules say, since a partial response is not the full one
+const RANGE_HEADERS: [&str; 2] = ["range", "if-range"];
+
+// What a cache key is made of. The default keys on the method, the URL and
+// every header as sent, so only byte-identical requests share an entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyRules {
+    // Only these headers participate when set, matched case-insensitively
+    headers: Option<Vec<String>>,
+    // Query parameters left out of the key; a trailing `*` matches a prefix
+    drop_params: Vec<String>,
+    sort_params: bool,
+    lowercase_path: bool,
+    // Methods whose request body is read and keyed on, e.g. `POST` for GraphQL
+    body_methods: Vec<String>,
+}
+
+impl KeyRules {
+    pub fn headers(mut self, names: &[&str]) -> Self {
+        self.headers = Some(names.iter().map(|name| name.to_ascii_lowercase()).collect());
+        self
+    }
+
+    pub fn drop_params(mut self, names: &[&str]) -> Self {
+        self.drop_params = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn sort_params(mut self) -> Self {
+        self.sort_params = true;
+        self
+    }
+
+    // For upstreams that treat paths case-insensitively
+    pub fn lowercase_path(mut self) -> Self {
+        self.lowercase_path = true;
+        self
+    }
+
+    // Opts these methods into caching. Only bodies sent with a Content-Length
+    // are keyed on; methods other than GET and HEAD are not cached otherwise.
+    pub fn cache_bodies(mut self, methods: &[&str]) -> Self {
+        self.body_methods = methods
+            .iter()
+            .map(|method| method.to_ascii_uppercase())
+            .collect();
+        self
+    }
+
+    fn drops(&self, param: &str) -> bool {
+        self.drop_params
+            .iter()
+            .any(|dropped| match dropped.strip_suffix('*') {
+                Some(prefix) => param.starts_with(prefix),
+                None => param == dropped,
+            })
+    }
+
+    fn normalize_url(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+        url.set_fragment(None);
+        if self.lowercase_path {
+            let path = url.path().to_lowercase();
+            url.set_path(&path);
+        }
+
+        //Rebuilding re-encodes the query, so leave it alone unless asked to
+        if url.query().is_some() && (self.sort_params || !self.drop_params.is_empty()) {
+            let mut params: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(name, _)| !self.drops(name))
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+            if self.sort_params {
+                params.sort();
+            }
+            if params.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(params);
+            }
+        }
+        url
+    }
+
+    // `headers_lines` as read from the client, each ending in `\r\n`
+    fn header_part(&self, headers_lines: &[String]) -> String {
+        if self.headers.is_none() {
+            return headers_lines.concat();
+        }
+        self.matched_headers(headers_lines)
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name.to_ascii_lowercase(), value))
+            .collect()
+    }
+
+    // The headers `header_part` keys on, in the order it keys them
+    fn matched_headers(&self, headers_lines: &[String]) -> Vec<(String, String)> {
+        let headers: Vec<(String, String)> = headers_lines
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let Some(names) = &self.headers else {
+            return headers;
+        };
+
+        let ranges = RANGE_HEADERS
+            .iter()
+            .filter(|range| !names.iter().any(|name| name == *range));
+        names
+            .iter()
+            .map(String::as_str)
+            .chain(ranges.copied())
+            .flat_map(|name| {
+                headers
+                    .iter()
+                    .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+                    .cloned()
+            })
+            .collect()
+    }
+}
+
+// Turns requests into cache keys following `KeyRules`, optionally overridden
+// per host or path. Path overrides win, the longest matching prefix first;
+// hosts match as `host:port` first, then as the bare host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheKeyBuilder {
+    rules: KeyRules,
+    hosts: HashMap<String, KeyRules>,
+    paths: Vec<(String, String, KeyRules)>,
+}
+
+impl CacheKeyBuilder {
+    pub fn new(rules: KeyRules) -> Self {
+        CacheKeyBuilder {
+            rules,
+            hosts: HashMap::new(),
+            paths: Vec::new(),
+        }
+    }
+
+    pub fn with_host(mut self, host: &str, rules: KeyRules) -> Self {
+        self.hosts.insert(host.to_ascii_lowercase(), rules);
+        self
+    }
+
+    pub fn with_path(mut self, host: &str, path_prefix: &str, rules: KeyRules) -> Self {
+        self.paths
+            .push((host.to_ascii_lowercase(), path_prefix.to_string(), rules));
+        self.paths
+            .sort_by_key(|(_, prefix, _)| std::cmp::Reverse(prefix.len()));
+        self
+    }
+
+    fn rules_for(&self, url: &Url) -> &KeyRules {
+        let host = url.host_str().unwrap_or("");
+        let authority = format!("{}:{}", host, url.port_or_known_default().unwrap_or(80));
+        let by_path = self.paths.iter().find(|(h, prefix, _)| {
+            (*h == authority || h == host) && url.path().starts_with(prefix.as_str())
+        });
+        match by_path {
+            Some((_, _, rules)) => rules,
+            None => self
+                .hosts
+                .get(&authority)
+                .or_else(|| self.hosts.get(host))
+                .unwrap_or(&self.rules),
+        }
+    }
+
+    // Whether the body of `method url` belongs in its cache key
+    pub fn caches_body(&self, method: &str, url: &Url) -> bool {
+        self.rules_for(url)
+            .body_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    // Whether requests to `url` only share an entry when all their headers match
+    pub fn keys_all_headers(&self, url: &Url) -> bool {
+        self.rules_for(url).headers.is_none()
+    }
+
+    // The request headers that set `method url` apart from other requests
+    pub fn matched_headers(&self, url: &Url, headers_lines: &[String]) -> Vec<(String, String)> {
+        self.rules_for(url).matched_headers(headers_lines)
+    }
+
+    // `body` is empty unless `caches_body`, which leaves the key as without one
+    pub fn key(&self, method: &str, url: &Url, headers_lines: &[String], body: &[u8]) -> String {
+        let rules = self.rules_for(url);
+        let head = format!(
+            "{} {}\r\n{}",
+            method,
+            rules.normalize_url(url),
+            rules.header_part(headers_lines)
+        );
+        let mut hasher = Sha256::new();
+        hasher.update(head.as_bytes());
+        if !body.is_empty() {
+            //length first, so no head and body can run into each other
+            hasher.update((body.len() as u64).to_be_bytes());
+            hasher.update(body);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(headers: &[&str]) -> Vec<String> {
+        let mut lines: Vec<String> = headers.iter().map(|h| format!("{}\r\n", h)).collect();
+        lines.push("\r\n".to_string());
+        lines
+    }
+
+    #[test]
+    fn test_default_rules_key_on_everything() {
+        let builder = CacheKeyBuilder::default();
+        let url = Url::parse("http://example.com/a?x=1").unwrap();
+        let key = builder.key("GET", &url, &lines(&["Accept: */*", "User-Agent: a"]), b"");
+
+        assert_eq!(
+            key,
+            builder.key("GET", &url, &lines(&["Accept: */*", "User-Agent: a"]), b"")
+        );
+        assert_ne!(
+            key,
+            builder.key("HEAD", &url, &lines(&["Accept: */*", "User-Agent: a"]), b"")
+        );
+        assert_ne!(
+            key,
+            builder.key("GET", &url, &lines(&["User-Agent: a", "Accept: */*"]), b"")
+        );
+        let other = Url::parse("http://example.com/a?x=2").unwrap();
+        assert_ne!(
+            key,
+            builder.key(
+                "GET",
+                &other,
+                &lines(&["Accept: */*", "User-Agent: a"]),
+                b""
+            )
+        );
+    }
+
+    #[test]
+    fn test_rules_normalize_requests() {
+        let rules = KeyRules::default()
+            .headers(&["Accept"])
+            .drop_params(&["utm_*", "fbclid"])
+            .sort_params()
+            .lowercase_path();
+        let builder = CacheKeyBuilder::new(rules);
+        let key = |url: &str, headers: &[&str]| {
+            builder.key("GET", &Url::parse(url).unwrap(), &lines(headers), b"")
+        };
+
+        let base = key("http://example.com/Page?b=2&a=1", &["Accept: text/html"]);
+        assert_eq!(
+            base,
+            key(
+                "http://EXAMPLE.com/page?a=1&utm_source=x&b=2&fbclid=y#top",
+                &["User-Agent: other", "accept:  text/html", "Cookie: id=1"]
+            )
+        );
+        assert_ne!(
+            base,
+            key("http://example.com/page?a=1&b=3", &["Accept: text/html"])
+        );
+        assert_ne!(
+            base,
+            key("http://example.com/page?a=1&b=2", &["Accept: */*"])
+        );
+        assert_eq!(
+            key("http://example.com/?utm_medium=mail", &[]),
+            key("http://example.com/", &[])
+        );
+        assert_ne!(
+            base,
+            key(
+                "http://example.com/page?a=1&b=2",
+                &["Accept: text/html", "Range: bytes=0-9"]
+            )
+        );
+        assert_eq!(
+            builder.matched_headers(
+                &Url::parse("http://example.com/").unwrap(),
+                &lines(&["If-Range: \"v1\"", "User-Agent: a", "accept: text/html"])
+            ),
+            vec![
+                ("accept".to_string(), "text/html".to_string()),
+                ("If-Range".to_string(), "\"v1\"".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_overrides() {
+        let builder = CacheKeyBuilder::default()
+            .with_host("example.com", KeyRules::default().headers(&[]))
+            .with_host("example.com:8080", KeyRules::default());
+        let key = |url: &str, headers: &[&str]| {
+            builder.key("GET", &Url::parse(url).unwrap(), &lines(headers), b"")
+        };
+
+        assert_eq!(
+            key("http://example.com/", &["A: 1"]),
+            key("http://example.com/", &["A: 2"])
+        );
+        assert_ne!(
+            key("http://example.com:8080/", &["A: 1"]),
+            key("http://example.com:8080/", &["A: 2"])
+        );
+        assert_ne!(
+            key("http://other.com/", &["A: 1"]),
+            key("http://other.com/", &["A: 2"])
+        );
+    }
+
+    #[test]
+    fn test_body_rules_per_path() {
+        let builder = CacheKeyBuilder::default()
+            .with_host("api.example.com", KeyRules::default().headers(&[]))
+            .with_path(
+                "api.example.com",
+                "/graphql",
+                KeyRules::default().headers(&[]).cache_bodies(&["post"]),
+            );
+        let graphql = Url::parse("http://api.example.com/graphql?v=1").unwrap();
+        let rest = Url::parse("http://api.example.com/users").unwrap();
+
+        assert!(builder.caches_body("POST", &graphql));
+        assert!(!builder.caches_body("PUT", &graphql));
+        assert!(!builder.caches_body("POST", &rest));
+
+        let key = |body: &[u8]| builder.key("POST", &graphql, &lines(&[]), body);
+        assert_eq!(key(b"{\"query\": 1}"), key(b"{\"query\": 1}"));
+        assert_ne!(key(b"{\"query\": 1}"), key(b"{\"query\": 2}"));
+        //A body cannot pass for the end of the head
+        let everything = CacheKeyBuilder::default();
+        let split = |head_lines: &[&str], body: &[u8]| {
+            let head_lines: Vec<String> = head_lines.iter().map(|l| l.to_string()).collect();
+            everything.key("POST", &graphql, &head_lines, body)
+        };
+        assert_ne!(
+            split(&["A: 1\r\n", "\r\n"], b"x"),
+            split(&["A: 1\r\n", "\r\nx"], b"")
+        );
+        assert_eq!(
+            builder.key("GET", &rest, &lines(&["A: 1"]), b""),
+            builder.key("GET", &rest, &lines(&["A: 2"]), b"")
+        );
+    }
+}
@@ -1,17 +1,52 @@
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
+use dashmap::DashMap;
 use sha2::{Digest, Sha256};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time::Instant;
-use tracing::info;
+use tracing::{error, info, warn};
 use url::Url;
 
-use cache::Cache;
-use cache::storage::{CacheStorage, InMemoryStorage, SimpleFileStorage};
+use cache::archive::{ArchiveFormat, ImportReport};
+use cache::storage::{CacheStorage, CassetteStorage, InMemoryStorage, SimpleFileStorage};
+use cache::{
+    Cache, CacheEntry, CacheError, CacheLookup, CacheWriter, EntryMetadata, StatsSnapshot,
+    UpstreamFailure,
+};
 use throttle::{InMemoryThrottler, Throttle};
 
+use coalesce::{InFlightRequests, Role};
+use range::{ByteRange, RangeAssembler};
+
+mod coalesce;
+mod http;
+mod key;
+mod prefetch;
+mod range;
+pub use key::{CacheKeyBuilder, KeyRules};
+pub use prefetch::{PrefetchFailure, PrefetchReport};
+
+const STALE_WARNING: &str = "110 - \"Response is Stale\"";
+const REVALIDATION_FAILED_WARNING: &str = "111 - \"Revalidation Failed\"";
+const OFFLINE_HEADER: &str = "X-Limiter-Offline";
+// Larger request bodies are forwarded uncached instead of buffered for the key
+const MAX_KEYED_BODY: usize = 1024 * 1024;
+
+// Whether `Server` may reach upstreams. Offline it answers from the cache
+// only, and with a `504` carrying `X-Limiter-Offline` when it cannot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OfflineMode {
+    #[default]
+    Online,
+    Offline,
+    // Expired entries are served too, however old
+    OfflineIgnoringTtl,
+}
+
 #[async_trait]
 pub trait Limiter {
     async fn run(self: Arc<Self>) {}
@@ -26,6 +61,16 @@ where
     port: u16,
     cache: Cache<T>,
     throttler: U,
+    refreshing: DashMap<String, ()>,
+    in_flight: InFlightRequests,
+    cache_errors: AtomicU64,
+    key_builder: RwLock<CacheKeyBuilder>,
+    assemble_ranges: AtomicBool,
+    ranges: RangeAssembler,
+    offline: RwLock<OfflineMode>,
+    offline_misses: Mutex<Vec<String>>,
+    // Set for record and replay, see `Server::record`
+    records_everything: AtomicBool,
 }
 
 impl Server<InMemoryStorage, InMemoryThrottler> {
@@ -36,16 +81,333 @@ impl Server<InMemoryStorage, InMemoryThrottler> {
         cache_ttl_seconds: &u64,
         throttle_duration_ms: u64,
     ) -> Arc<Self> {
+        Server::new(
+            ip,
+            port,
+            Cache::new(cache_size, cache_ttl_seconds),
+            InMemoryThrottler::new(throttle_duration_ms),
+        )
+    }
+}
+
+// Record/replay for deterministic tests. Requests are matched by their cache
+// key, so replay with the key builder used while recording. Every exchange is
+// kept, whatever its method or status and ranges included, along with the
+// request's method, URL, keyed headers and body. Requests with chunked bodies
+// or bodies over `MAX_KEYED_BODY` are passed through unrecorded.
+impl Server<CassetteStorage, InMemoryThrottler> {
+    // Fetches every request upstream and writes the exchanges to the cassette
+    // at `path`, replacing it. A request made twice keeps its last response.
+    pub async fn record(
+        ip: &str,
+        port: u16,
+        path: &str,
+        throttle_duration_ms: u64,
+    ) -> Result<Arc<Self>, CacheError> {
+        let storage = CassetteStorage::create(path).await?;
+        let server = Server::new(
+            ip,
+            port,
+            Cache::with_storage(&usize::MAX, &0, storage),
+            InMemoryThrottler::new(throttle_duration_ms),
+        );
+        server.records_everything.store(true, Ordering::Relaxed);
+        Ok(server)
+    }
+
+    // Answers only from the cassette at `path`, offline. Unmatched requests
+    // get a `504` and are logged as errors; check `offline_misses` at the end.
+    pub async fn replay(ip: &str, port: u16, path: &str) -> Result<Arc<Self>, CacheError> {
+        let storage = CassetteStorage::open(path).await?;
+        let server = Server::new(
+            ip,
+            port,
+            Cache::with_storage(&usize::MAX, &0, storage),
+            InMemoryThrottler::new(0),
+        );
+        server.set_offline(OfflineMode::Offline);
+        server.records_everything.store(true, Ordering::Relaxed);
+        Ok(server)
+    }
+}
+
+impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
+    pub fn new(ip: &str, port: u16, cache: Cache<T>, throttler: U) -> Arc<Self> {
         Arc::new(Server {
             ip: ip.to_string(),
             port,
-            cache: Cache::new(cache_size, cache_ttl_seconds),
-            throttler: InMemoryThrottler::new(throttle_duration_ms),
+            cache,
+            throttler,
+            refreshing: DashMap::new(),
+            in_flight: InFlightRequests::default(),
+            cache_errors: AtomicU64::new(0),
+            key_builder: RwLock::new(CacheKeyBuilder::default()),
+            assemble_ranges: AtomicBool::new(false),
+            ranges: RangeAssembler::default(),
+            offline: RwLock::new(OfflineMode::default()),
+            offline_misses: Mutex::new(Vec::new()),
+            records_everything: AtomicBool::new(false),
         })
     }
-}
 
-impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
+    pub fn cache(&self) -> &Cache<T> {
+        &self.cache
+    }
+
+    // Cache failures seen so far; each one degraded to serving from upstream
+    pub fn cache_errors(&self) -> u64 {
+        self.cache_errors.load(Ordering::Relaxed)
+    }
+
+    fn cache_failed(&self, cache_key: &str, e: &CacheError) {
+        self.cache_errors.fetch_add(1, Ordering::Relaxed);
+        match e {
+            CacheError::Corrupted(_) => warn!("{}, treating as a miss", e),
+            CacheError::Capacity(_) => error!("{}, not caching key: {}", e, cache_key),
+            _ => error!("{} (key: {})", e, cache_key),
+        }
+    }
+
+    // Hit, miss and eviction counters of the cache, globally and per host
+    pub fn stats(&self) -> StatsSnapshot {
+        self.cache.stats()
+    }
+
+    // A broken cache must not break proxying, so failures become misses.
+    // `url` attributes misses to its host in the stats.
+    async fn lookup(&self, cache_key: &str, url: Option<&Url>) -> CacheLookup {
+        let lookup = match url {
+            Some(url) => self.cache.lookup_for_url(cache_key, url.as_str()).await,
+            None => self.cache.lookup(cache_key).await,
+        };
+        lookup.unwrap_or_else(|e| {
+            self.cache_failed(cache_key, &e);
+            CacheLookup::Miss
+        })
+    }
+
+    // Removes one entry by its cache key; returns whether it was cached
+    pub async fn invalidate(&self, cache_key: &str) -> Result<bool, CacheError> {
+        self.cache.invalidate(cache_key).await
+    }
+
+    // Every cached variant of `method url`, whatever headers it was requested with
+    pub async fn invalidate_url(&self, method: &str, url: &str) -> Result<usize, CacheError> {
+        self.cache
+            .invalidate_url(method, &Self::normalize_url(url))
+            .await
+    }
+
+    pub async fn invalidate_prefix(&self, url_prefix: &str) -> Result<usize, CacheError> {
+        self.cache
+            .invalidate_prefix(&Self::normalize_url(url_prefix))
+            .await
+    }
+
+    // Purges everything fetched from `host`, e.g. after a bad deploy upstream
+    pub async fn invalidate_host(&self, host: &str) -> Result<usize, CacheError> {
+        self.cache.invalidate_host(host).await
+    }
+
+    // Tags come from the upstream `Cache-Tag` and `Surrogate-Key` headers
+    pub async fn invalidate_tag(&self, tag: &str) -> Result<usize, CacheError> {
+        self.cache.invalidate_tag(tag).await
+    }
+
+    // Loads an archive, e.g. a HAR captured by a browser. Records without a
+    // cache key are keyed like a request for their method and URL without any
+    // headers, so clients only hit them under key rules that leave headers out.
+    pub async fn import(
+        &self,
+        path: &str,
+        format: ArchiveFormat,
+    ) -> Result<ImportReport, CacheError> {
+        self.cache
+            .import(path, format, |metadata| {
+                let url = Url::parse(metadata.url.as_deref()?).ok()?;
+                let method = metadata.method.as_deref().unwrap_or("GET");
+                Some(self.cache_key(method, &url, &[], &[]))
+            })
+            .await
+    }
+
+    pub async fn export(&self, path: &str, format: ArchiveFormat) -> Result<usize, CacheError> {
+        self.cache.export(path, format).await
+    }
+
+    //Entries record the URL as `Url` formats it, so lookups must match that
+    fn normalize_url(url: &str) -> String {
+        Url::parse(url)
+            .map(String::from)
+            .unwrap_or_else(|_| url.to_string())
+    }
+
+    fn request_metadata(mut metadata: EntryMetadata, method: &str, url: &Url) -> EntryMetadata {
+        metadata.method = Some(method.to_string());
+        metadata.url = Some(url.to_string());
+        metadata
+    }
+
+    // Recordings show what each response answers, see `Server::record`
+    fn recorded_request(
+        &self,
+        mut metadata: EntryMetadata,
+        url: &Url,
+        headers_lines: &[String],
+        body: &[u8],
+    ) -> EntryMetadata {
+        if self.records_everything.load(Ordering::Relaxed) {
+            metadata.request_headers = self
+                .key_builder
+                .read()
+                .unwrap()
+                .matched_headers(url, headers_lines);
+            metadata.request_body = Some(body.to_vec()).filter(|body| !body.is_empty());
+        }
+        metadata
+    }
+
+    // Which parts of a request its cache key is made of. Entries cached under
+    // the previous rules are no longer found.
+    pub fn set_key_builder(&self, key_builder: CacheKeyBuilder) {
+        *self.key_builder.write().unwrap() = key_builder;
+    }
+
+    fn keys_all_headers(&self, url: &Url) -> bool {
+        self.key_builder.read().unwrap().keys_all_headers(url)
+    }
+
+    fn cache_key(&self, method: &str, url: &Url, headers_lines: &[String], body: &[u8]) -> String {
+        self.key_builder
+            .read()
+            .unwrap()
+            .key(method, url, headers_lines, body)
+    }
+
+    // The request body when the key rules opt `method url` into caching, or
+    // while recording, and it fits, `None` when the request must not be
+    // cached. A client waiting for `100 Continue` gets it here, and its
+    // `Expect` is dropped since the upstream receives the body right away.
+    async fn read_keyed_body<R: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        method: &str,
+        url: &Url,
+        headers_lines: &mut Vec<String>,
+        client_stream: &mut R,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        if !(self.records_everything.load(Ordering::Relaxed)
+            || self.key_builder.read().unwrap().caches_body(method, url))
+            || http::request_header(headers_lines, "Transfer-Encoding").is_some()
+        {
+            return Ok(None);
+        }
+        let length = match http::request_header(headers_lines, "Content-Length") {
+            Some(length) => match length.parse::<usize>() {
+                Ok(length) if length <= MAX_KEYED_BODY => length,
+                _ => return Ok(None),
+            },
+            None => 0,
+        };
+
+        if http::request_header(headers_lines, "Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+        {
+            client_stream
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await?;
+            client_stream.flush().await?;
+            headers_lines.retain(|line| {
+                !line
+                    .split_once(':')
+                    .is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("Expect"))
+            });
+        }
+
+        let mut body = vec![0u8; length];
+        client_stream.read_exact(&mut body).await?;
+        Ok(Some(body))
+    }
+
+    // Range requests are served from cached full responses. With this on,
+    // a client downloading an object in order also caches it whole.
+    pub fn set_assemble_ranges(&self, assemble: bool) {
+        self.assemble_ranges.store(assemble, Ordering::Relaxed);
+    }
+
+    // For reproducible runs against whatever the cache holds
+    pub fn set_offline(&self, mode: OfflineMode) {
+        *self.offline.write().unwrap() = mode;
+    }
+
+    fn offline_mode(&self) -> OfflineMode {
+        *self.offline.read().unwrap()
+    }
+
+    fn is_recording(&self) -> bool {
+        self.records_everything.load(Ordering::Relaxed)
+            && self.offline_mode() == OfflineMode::Online
+    }
+
+    // The stored metadata if the lookup may be answered from the cache
+    fn servable(&self, lookup: CacheLookup) -> Option<EntryMetadata> {
+        match lookup {
+            CacheLookup::Fresh(metadata) => Some(metadata),
+            CacheLookup::Stale { metadata, .. }
+                if self.offline_mode() == OfflineMode::OfflineIgnoringTtl =>
+            {
+                Some(metadata)
+            }
+            _ => None,
+        }
+    }
+
+    // Requests answered with a `504` while offline, in arrival order, e.g.
+    // ones a replayed cassette has no recording for
+    pub fn offline_misses(&self) -> Vec<String> {
+        self.offline_misses.lock().unwrap().clone()
+    }
+
+    async fn serve_offline_miss<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        request: String,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        error!("Offline and not cached: {}", request);
+        self.offline_misses.lock().unwrap().push(request);
+        let body = b"Not cached, and the proxy is offline\n";
+        let metadata = EntryMetadata {
+            status: Some(504),
+            reason: "Gateway Timeout".to_string(),
+            headers: vec![
+                ("Content-Type".to_string(), "text/plain".to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+                ("Connection".to_string(), "close".to_string()),
+                (OFFLINE_HEADER.to_string(), "miss".to_string()),
+            ],
+            ..EntryMetadata::default()
+        };
+        let head = http::response_head(&metadata);
+        let head = http::insert_cache_headers(&head, "MISS", Duration::ZERO);
+
+        stream.write_all(&head).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn open_cache_writer(
+        &self,
+        cache_key: &str,
+        metadata: EntryMetadata,
+    ) -> Option<CacheWriter<'_, T>> {
+        self.cache
+            .open_write(cache_key, metadata)
+            .await
+            .inspect_err(|e| self.cache_failed(cache_key, e))
+            .ok()
+    }
+
     async fn handle_connection(
         &self,
         client_stream: TcpStream,
@@ -72,14 +434,8 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
                     .await
             }
             _ => {
-                self.handle_else_methods(
-                    &method,
-                    &host_or_url,
-                    &version,
-                    client_stream_reader,
-                    first_line,
-                )
-                .await
+                self.handle_else_methods(&method, &host_or_url, &version, client_stream_reader)
+                    .await
             }
         }
     }
@@ -102,22 +458,29 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
             }
         }
 
+        //Tunnels are opaque, so only byte-identical ones share an entry
         let mut hasher = Sha256::new();
-        let cache_key_str = format!("{}{}", host, request_buffer);
-        hasher.update(cache_key_str.as_bytes());
+        hasher.update(format!("{}{}", host, request_buffer).as_bytes());
         let cache_key = hex::encode(hasher.finalize());
 
-        if let Some(cached_response) = self.cache.get(&cache_key).await {
+        let lookup = self.lookup(&cache_key, None).await;
+        if self.servable(lookup).is_some() {
             info!("Cache HIT for key: {}", cache_key);
-
-            let stream = client_stream_reader.get_mut();
-            stream.write_all(&cached_response).await?;
-            stream.flush().await?;
-            stream.shutdown().await?;
-
-            return Ok(());
+            return self
+                .serve_cached(
+                    client_stream_reader.get_mut(),
+                    &cache_key,
+                    None,
+                    Duration::ZERO,
+                )
+                .await;
         }
 
+        if self.offline_mode() != OfflineMode::Online {
+            return self
+                .serve_offline_miss(client_stream_reader.get_mut(), format!("CONNECT {}", host))
+                .await;
+        }
         self.throttler.throttle(host).await;
 
         let target_stream = TcpStream::connect(host).await?;
@@ -134,7 +497,9 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
         let upstream_task =
             tokio::spawn(async move { tokio::io::copy(&mut client_read, &mut target_write).await });
 
-        let mut cache_buffer = Vec::new();
+        let mut cache_writer = self
+            .open_cache_writer(&cache_key, EntryMetadata::default())
+            .await;
         let mut buffer = [0u8; 8192];
         loop {
             let n = target_read.read(&mut buffer).await?;
@@ -142,12 +507,12 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
                 break;
             }
             client_write.write_all(&buffer[..n]).await?;
-            cache_buffer.extend_from_slice(&buffer[..n]);
+            Self::tee(&mut cache_writer, &buffer[..n]).await;
         }
 
         let _ = upstream_task.await;
 
-        self.cache.put(&cache_key, &cache_buffer).await.ok();
+        Self::commit(cache_writer).await;
         Ok(())
     }
 
@@ -157,7 +522,6 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
         url_str: &str,
         version: &str,
         mut client_stream_reader: BufReader<TcpStream>,
-        first_line: String,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let url = Url::parse(url_str)?;
         let target_host = url.host_str().ok_or("Invalid host")?;
@@ -165,7 +529,6 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
         let target_addr = format!("{}:{}", target_host, target_port);
 
         let mut headers_lines: Vec<String> = Vec::new();
-        let mut full_request_str = first_line.clone();
 
         loop {
             let mut line = String::new();
@@ -173,7 +536,6 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
                 break;
             }
 
-            full_request_str.push_str(&line);
             headers_lines.push(line.clone());
 
             if line.trim().is_empty() {
@@ -181,40 +543,201 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
             }
         }
 
-        let mut hasher = Sha256::new();
-        let cache_key_str = format!("{}{}", target_addr, full_request_str);
-        hasher.update(cache_key_str.as_bytes());
-        let cache_key = hex::encode(hasher.finalize());
+        //Recordings keep each range as it was answered instead
+        if method == "GET"
+            && !self.records_everything.load(Ordering::Relaxed)
+            && let Some(range) = http::request_header(&headers_lines, "Range")
+        {
+            let range = ByteRange::parse(range);
+            return self
+                .handle_range(
+                    range,
+                    &url,
+                    version,
+                    &target_addr,
+                    &headers_lines,
+                    client_stream_reader,
+                )
+                .await;
+        }
 
-        if let Some(cached_response) = self.cache.get(&cache_key).await {
-            info!("Cache HIT for key: {}", cache_key);
+        let body = self
+            .read_keyed_body(method, &url, &mut headers_lines, &mut client_stream_reader)
+            .await?;
+        //Only GET and HEAD are cached as they are; other methods have side
+        //effects or bodies that are not part of the key unless opted in
+        if body.is_none() && !matches!(method, "GET" | "HEAD") {
+            if self.offline_mode() != OfflineMode::Online {
+                let request = format!("{} {}", method, url);
+                return self
+                    .serve_offline_miss(client_stream_reader.get_mut(), request)
+                    .await;
+            }
+            let upstream_request =
+                Self::build_upstream_request(method, &url, version, &headers_lines, None);
+            return self
+                .pass_through(&target_addr, &upstream_request, client_stream_reader)
+                .await;
+        }
+        let body = body.unwrap_or_default();
+        let cache_key = self.cache_key(method, &url, &headers_lines, &body);
 
-            let stream = client_stream_reader.get_mut();
-            stream.write_all(&cached_response).await?;
-            stream.flush().await?;
-            stream.shutdown().await?;
+        //Recording forwards every request as sent, never revalidating on the
+        //client's behalf, so the cassette holds the exchanges that took place
+        let lookup = if self.is_recording() {
+            CacheLookup::Miss
+        } else {
+            self.lookup(&cache_key, Some(&url)).await
+        };
+        let (stale_metadata, stale_seconds) = match lookup {
+            CacheLookup::Fresh(_) => {
+                info!("Cache HIT for key: {}", cache_key);
+                return self
+                    .serve_cached(
+                        client_stream_reader.get_mut(),
+                        &cache_key,
+                        None,
+                        Duration::ZERO,
+                    )
+                    .await;
+            }
+            CacheLookup::Stale {
+                metadata,
+                stale_seconds,
+            } => (Some(metadata), stale_seconds),
+            CacheLookup::Miss => (None, 0),
+        };
 
-            return Ok(());
+        match self.offline_mode() {
+            OfflineMode::Online => {}
+            OfflineMode::OfflineIgnoringTtl if stale_metadata.is_some() => {
+                info!("Offline, serving stale entry for key: {}", cache_key);
+                return self
+                    .serve_cached(
+                        client_stream_reader.get_mut(),
+                        &cache_key,
+                        Some(STALE_WARNING),
+                        Duration::ZERO,
+                    )
+                    .await;
+            }
+            _ => {
+                return self
+                    .serve_offline_miss(
+                        client_stream_reader.get_mut(),
+                        format!("{} {}", method, url),
+                    )
+                    .await;
+            }
         }
 
-        self.throttler.throttle(&target_addr).await;
+        //Identical concurrent misses share one upstream fetch. Only for methods
+        //whose request body cannot differ behind the same cache key.
+        let mut leader = None;
+        let coalescable = matches!(method, "GET" | "HEAD") || !body.is_empty();
+        if stale_metadata.is_none() && coalescable {
+            match self.in_flight.join(&cache_key) {
+                Role::Leader(role) => leader = Some(role),
+                Role::Follower(follower) => {
+                    info!("Joining in-flight request for key: {}", cache_key);
+                    let stream = client_stream_reader.get_mut();
+                    if follower.stream_to(stream).await? {
+                        stream.flush().await?;
+                        stream.shutdown().await?;
+                        return Ok(());
+                    }
+                    //the leading fetch failed before sending anything, fetch on our own
+                }
+                Role::Bypass => {}
+            }
+        }
 
-        let mut target_stream = TcpStream::connect(&target_addr).await?;
+        //Only revalidate on our own behalf; a client-sent conditional expects the 304 itself
+        let client_is_conditional = http::request_header(&headers_lines, "If-None-Match")
+            .or_else(|| http::request_header(&headers_lines, "If-Modified-Since"))
+            .is_some();
+        let conditional_headers = stale_metadata
+            .as_ref()
+            .filter(|_| !client_is_conditional)
+            .and_then(http::conditional_headers);
 
-        let path = url.path();
-        let path_and_query = match url.query() {
-            Some(q) => format!("{}?{}", path, q),
-            None => path.to_string(),
-        };
+        let mut upstream_request = Self::build_upstream_request(
+            method,
+            &url,
+            version,
+            &headers_lines,
+            conditional_headers.as_deref(),
+        );
+        upstream_request.extend_from_slice(&body);
 
-        let new_request_line = format!("{} {} {}\r\n", method, path_and_query, version);
-        target_stream.write_all(new_request_line.as_bytes()).await?;
+        if stale_metadata.is_some() && self.cache.within_stale_while_revalidate(stale_seconds) {
+            info!("Serving stale entry while revalidating key: {}", cache_key);
+            self.serve_cached(
+                client_stream_reader.get_mut(),
+                &cache_key,
+                Some(STALE_WARNING),
+                Duration::ZERO,
+            )
+            .await?;
 
-        for line in headers_lines {
-            if !line.to_lowercase().starts_with("proxy-") {
-                target_stream.write_all(line.as_bytes()).await?;
+            self.refresh_in_background(
+                &target_addr,
+                &cache_key,
+                &upstream_request,
+                conditional_headers.is_some(),
+                (method, &url),
+            )
+            .await;
+            return Ok(());
+        }
+
+        let stale_if_error =
+            stale_metadata.is_some() && self.cache.within_stale_if_error(stale_seconds);
+
+        let waited = self.throttle(&target_addr).await;
+
+        let mut target_stream = match Self::connect_upstream(&target_addr).await {
+            Ok(target_stream) => target_stream,
+            Err((e, _)) if stale_if_error => {
+                info!(
+                    "Upstream {} unreachable ({}), serving stale entry",
+                    target_addr, e
+                );
+                return self
+                    .serve_cached(
+                        client_stream_reader.get_mut(),
+                        &cache_key,
+                        Some(REVALIDATION_FAILED_WARNING),
+                        waited,
+                    )
+                    .await;
+            }
+            Err((e, Some(failure))) if self.cache.caches_failures() => {
+                info!(
+                    "Upstream {} unreachable ({}), caching the failure",
+                    target_addr, e
+                );
+                let response = self
+                    .cache_failure(&cache_key, (method, &url), failure, &e)
+                    .await;
+                let response = http::insert_cache_headers(&response, "MISS", waited);
+                let stream = client_stream_reader.get_mut();
+                stream.write_all(&response).await?;
+                if let Some(leader) = leader {
+                    leader.push(&response);
+                    leader.finish();
+                }
+                stream.flush().await?;
+                stream.shutdown().await?;
+                return Ok(());
             }
+            Err((e, _)) => return Err(e.into()),
+        };
+
+        if conditional_headers.is_some() {
+            info!("Revalidating stale entry for key: {}", cache_key);
         }
+        target_stream.write_all(&upstream_request).await?;
 
         let (mut target_read, mut target_write) = tokio::io::split(target_stream);
         let (mut client_read, mut client_write) = tokio::io::split(client_stream_reader);
@@ -222,7 +745,55 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
         let upstream_task =
             tokio::spawn(async move { tokio::io::copy(&mut client_read, &mut target_write).await });
 
-        let mut cache_buffer = Vec::new();
+        let mut head_buffer = Vec::new();
+        let head_len = http::read_head(&mut target_read, &mut head_buffer).await?;
+        let head = http::ResponseHead::parse(&head_buffer);
+        let status = head.as_ref().map(|head| head.status);
+
+        if conditional_headers.is_some() && status == Some(304) {
+            info!("Revalidated stale entry for key: {}", cache_key);
+            upstream_task.abort();
+            let headers = head.map(|head| head.headers).unwrap_or_default();
+            if let Err(e) = self.cache.refresh(&cache_key, &headers).await {
+                self.cache_failed(&cache_key, &e);
+            }
+            return self
+                .serve_cached(&mut client_write, &cache_key, None, waited)
+                .await;
+        }
+
+        if stale_if_error && status.is_some_and(|status| status >= 500) {
+            info!("Upstream {} failed, serving stale entry", target_addr);
+            upstream_task.abort();
+            return self
+                .serve_cached(
+                    &mut client_write,
+                    &cache_key,
+                    Some(REVALIDATION_FAILED_WARNING),
+                    waited,
+                )
+                .await;
+        }
+
+        let served_head = match head {
+            Some(_) => http::insert_cache_headers(&head_buffer, "MISS", waited),
+            None => head_buffer.clone(),
+        };
+        client_write.write_all(&served_head).await?;
+        if let Some(leader) = &leader {
+            leader.push(&served_head);
+        }
+
+        //The body streams into the cache as it arrives instead of being buffered
+        let (metadata, body_start) = match (head, head_len) {
+            (Some(head), Some(head_len)) => (head.to_metadata(), head_len),
+            _ => (EntryMetadata::default(), 0),
+        };
+        let metadata = Self::request_metadata(metadata, method, &url);
+        let metadata = self.recorded_request(metadata, &url, &headers_lines, &body);
+        let mut cache_writer = self.open_cache_writer(&cache_key, metadata).await;
+        Self::tee(&mut cache_writer, &head_buffer[body_start..]).await;
+
         let mut buffer = [0u8; 8192];
         loop {
             let n = target_read.read(&mut buffer).await?;
@@ -231,90 +802,486 @@ impl<T: CacheStorage + Send + Sync, U: Throttle + Send + Sync> Server<T, U> {
             }
 
             client_write.write_all(&buffer[..n]).await?;
-            cache_buffer.extend_from_slice(&buffer[..n]);
+            Self::tee(&mut cache_writer, &buffer[..n]).await;
+            if let Some(leader) = &leader {
+                leader.push(&buffer[..n]);
+            }
         }
 
         let _ = upstream_task.await;
 
-        self.cache.put(&cache_key, &cache_buffer).await.ok();
+        //Store before releasing the followers so later arrivals hit the cache
+        Self::commit(cache_writer).await;
+        if let Some(leader) = leader {
+            leader.finish();
+        }
         Ok(())
     }
-}
-
-#[async_trait]
-impl<T: CacheStorage + Send + Sync + 'static, U: Throttle + Send + Sync + 'static> Limiter
-    for Server<T, U>
-{
-    async fn run(self: Arc<Self>) {
-        info!("Starting the server...");
-        let listener = TcpListener::bind(format!("{}:{}", self.ip, self.port))
-            .await
-            .expect("Failed to bind to address");
-
-        loop {
-            let (client_stream, addr) = listener
-                .accept()
-                .await
-                .expect("Failed to accept connection");
 
-            let server_clone = self.clone();
+    // Ranges share the cache entry of the full response, keyed without the
+    // range headers. `range` is `None` for ranges the cache cannot serve.
+    async fn handle_range(
+        &self,
+        range: Option<ByteRange>,
+        url: &Url,
+        version: &str,
+        target_addr: &str,
+        headers_lines: &[String],
+        mut client_stream_reader: BufReader<TcpStream>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let full_lines: Vec<String> = headers_lines
+            .iter()
+            .filter(|line| {
+                let name = line.split(':').next().unwrap_or("").trim();
+                !name.eq_ignore_ascii_case("Range") && !name.eq_ignore_ascii_case("If-Range")
+            })
+            .cloned()
+            .collect();
+        let full_key = self.cache_key("GET", url, &full_lines, &[]);
 
-            tokio::spawn(async move {
-                if let Err(e) = server_clone.handle_connection(client_stream).await {
-                    eprintln!("Error handling connection from {}: {}", addr, e);
-                }
-            });
+        if let Some(range) = range
+            && let Some(metadata) = self.servable(self.lookup(&full_key, Some(url)).await)
+            && metadata.status == Some(200)
+            && let Some(total) = metadata
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok())
+        {
+            let stream = client_stream_reader.get_mut();
+            //A changed validator means the client's other parts are outdated
+            if let Some(if_range) = http::request_header(headers_lines, "If-Range")
+                && metadata.header("ETag") != Some(if_range)
+                && metadata.header("Last-Modified") != Some(if_range)
+            {
+                info!("Cache HIT for key: {} (If-Range mismatch)", full_key);
+                return self
+                    .serve_cached(stream, &full_key, None, Duration::ZERO)
+                    .await;
+            }
+            info!("Cache HIT for key: {} (range)", full_key);
+            return self
+                .serve_range(stream, &full_key, metadata, range.resolve(total), total)
+                .await;
         }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::TcpListener;
-
-    #[tokio::test]
-    async fn test_proxy_server_caches_requests() {
-        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let upstream_addr = upstream_listener.local_addr().unwrap();
 
-        let hit_counter = Arc::new(AtomicUsize::new(0));
-        let hit_counter_clone = hit_counter.clone();
+        if self.offline_mode() != OfflineMode::Online {
+            return self
+                .serve_offline_miss(client_stream_reader.get_mut(), format!("GET {}", url))
+                .await;
+        }
+        let upstream_request =
+            Self::build_upstream_request("GET", url, version, headers_lines, None);
+        let waited = self.throttle(target_addr).await;
+        let mut target_stream = Self::connect_upstream(target_addr)
+            .await
+            .map_err(|(e, _)| e)?;
+        target_stream.write_all(&upstream_request).await?;
 
-        tokio::spawn(async move {
-            loop {
-                if let Ok((mut socket, _)) = upstream_listener.accept().await {
-                    let counter = hit_counter_clone.clone();
-                    tokio::spawn(async move {
-                        counter.fetch_add(1, Ordering::SeqCst);
+        let (mut target_read, mut target_write) = tokio::io::split(target_stream);
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream_reader);
+        let upstream_task =
+            tokio::spawn(async move { tokio::io::copy(&mut client_read, &mut target_write).await });
 
-                        let mut buf = [0u8; 1024];
-                        let _ = socket.read(&mut buf).await;
+        let mut head_buffer = Vec::new();
+        let head_len = http::read_head(&mut target_read, &mut head_buffer).await?;
+        let head = http::ResponseHead::parse(&head_buffer);
+        if head.is_some() {
+            let served_head = http::insert_cache_headers(&head_buffer, "MISS", waited);
+            client_write.write_all(&served_head).await?;
+        } else {
+            client_write.write_all(&head_buffer).await?;
+        }
+        let head =
+            head.filter(|head| head.status == 206 && self.assemble_ranges.load(Ordering::Relaxed));
 
-                        let response = "HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\nHello World!";
-                        socket.write_all(response.as_bytes()).await.unwrap();
-                        socket.flush().await.unwrap();
-                    });
-                }
+        let mut part = match (&head, head_len) {
+            (Some(_), Some(head_len)) => head_buffer[head_len..].to_vec(),
+            _ => Vec::new(),
+        };
+        let mut buffer = [0u8; 8192];
+        loop {
+            let n = target_read.read(&mut buffer).await?;
+            if n == 0 {
+                break;
             }
-        });
+            client_write.write_all(&buffer[..n]).await?;
+            if head.is_some() {
+                part.extend_from_slice(&buffer[..n]);
+            }
+        }
+        upstream_task.abort();
+        client_write.shutdown().await?;
 
-        let proxy_port = 9595;
-        let server = Server::new_in_memory("127.0.0.1", proxy_port, &1024, &60, 10);
+        if let Some(head) = head
+            && let Some(mut entry) = self.ranges.add(&full_key, &head, &part)
+        {
+            info!("Assembled full response from ranges for key: {}", full_key);
+            entry.metadata = Self::request_metadata(entry.metadata, "GET", url);
+            if let Err(e) = self.cache.put(&full_key, entry).await {
+                self.cache_failed(&full_key, &e);
+            }
+        }
+        Ok(())
+    }
 
-        let server_handle = tokio::spawn(async move {
-            server.run().await;
+    // `206` with the `first..=last` bytes of a cached full body, or `416`
+    async fn serve_range<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        cache_key: &str,
+        mut metadata: EntryMetadata,
+        range: Option<(u64, u64)>,
+        total: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        metadata.headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Content-Range")
         });
+        let Some((first, last)) = range else {
+            metadata.status = Some(416);
+            metadata.reason = "Range Not Satisfiable".to_string();
+            metadata.headers = vec![
+                ("Content-Range".to_string(), format!("bytes */{}", total)),
+                ("Content-Length".to_string(), "0".to_string()),
+            ];
+            let head = http::response_head(&metadata);
+            let head = http::insert_cache_headers(&head, "HIT", Duration::ZERO);
+            stream.write_all(&head).await?;
+            stream.flush().await?;
+            stream.shutdown().await?;
+            return Ok(());
+        };
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        let (_, mut reader) = self
+            .cache
+            .open_read(cache_key)
+            .await?
+            .ok_or("Cached entry vanished")?;
+        metadata.status = Some(206);
+        metadata.reason = "Partial Content".to_string();
+        metadata.headers.push((
+            "Content-Range".to_string(),
+            format!("bytes {}-{}/{}", first, last, total),
+        ));
+        metadata
+            .headers
+            .push(("Content-Length".to_string(), (last - first + 1).to_string()));
+        http::set_age(&mut metadata, Self::now());
 
-        let proxy_url = format!("http://127.0.0.1:{}", proxy_port);
-        let client = reqwest::Client::builder()
-            .proxy(reqwest::Proxy::http(&proxy_url).unwrap())
-            .build()
-            .unwrap();
+        let head = http::response_head(&metadata);
+        let head = http::insert_cache_headers(&head, "HIT", Duration::ZERO);
+        stream.write_all(&head).await?;
+        tokio::io::copy(&mut (&mut reader).take(first), &mut tokio::io::sink()).await?;
+        tokio::io::copy(&mut (&mut reader).take(last - first + 1), stream).await?;
+        stream.flush().await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    // Proxies a request the cache must stay out of, in both directions as is
+    async fn pass_through(
+        &self,
+        target_addr: &str,
+        upstream_request: &[u8],
+        client_stream_reader: BufReader<TcpStream>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let waited = self.throttle(target_addr).await;
+        let mut target_stream = Self::connect_upstream(target_addr)
+            .await
+            .map_err(|(e, _)| e)?;
+        target_stream.write_all(upstream_request).await?;
+
+        let (mut target_read, mut target_write) = tokio::io::split(target_stream);
+        let (mut client_read, mut client_write) = tokio::io::split(client_stream_reader);
+        let upstream_task =
+            tokio::spawn(async move { tokio::io::copy(&mut client_read, &mut target_write).await });
+
+        let mut head_buffer = Vec::new();
+        http::read_head(&mut target_read, &mut head_buffer).await?;
+        if http::ResponseHead::parse(&head_buffer).is_some() {
+            head_buffer = http::insert_cache_headers(&head_buffer, "MISS", waited);
+        }
+        client_write.write_all(&head_buffer).await?;
+        tokio::io::copy(&mut target_read, &mut client_write).await?;
+        upstream_task.abort();
+        client_write.shutdown().await?;
+        Ok(())
+    }
+
+    // Time spent waiting for the throttler, reported in `X-Limiter-Wait-Ms`
+    async fn throttle(&self, target_addr: &str) -> Duration {
+        let started = Instant::now();
+        self.throttler.throttle(target_addr).await;
+        started.elapsed()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // Failures worth caching are told apart, see `Cache::set_negative_ttl`
+    async fn connect_upstream(
+        target_addr: &str,
+    ) -> Result<TcpStream, (std::io::Error, Option<UpstreamFailure>)> {
+        let addrs: Vec<_> = tokio::net::lookup_host(target_addr)
+            .await
+            .map_err(|e| (e, Some(UpstreamFailure::Dns)))?
+            .collect();
+        TcpStream::connect(addrs.as_slice()).await.map_err(|e| {
+            let failure = (e.kind() == std::io::ErrorKind::ConnectionRefused)
+                .then_some(UpstreamFailure::ConnectionRefused);
+            (e, failure)
+        })
+    }
+
+    // Stores a 502 standing in for the unreachable upstream and returns it
+    async fn cache_failure(
+        &self,
+        cache_key: &str,
+        (method, url): (&str, &Url),
+        failure: UpstreamFailure,
+        error: &std::io::Error,
+    ) -> Vec<u8> {
+        let body = format!("Upstream unreachable: {}\n", error).into_bytes();
+        let metadata = EntryMetadata {
+            status: Some(502),
+            reason: "Bad Gateway".to_string(),
+            headers: vec![
+                ("Content-Type".to_string(), "text/plain".to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+                ("Connection".to_string(), "close".to_string()),
+            ],
+            failure: Some(failure),
+            ..EntryMetadata::default()
+        };
+        let metadata = Self::request_metadata(metadata, method, url);
+
+        let mut response = http::response_head(&metadata);
+        response.extend_from_slice(&body);
+        let entry = CacheEntry { metadata, body };
+        if let Err(e) = self.cache.put(cache_key, entry).await {
+            self.cache_failed(cache_key, &e);
+        }
+        response
+    }
+
+    fn build_upstream_request(
+        method: &str,
+        url: &Url,
+        version: &str,
+        headers_lines: &[String],
+        conditional_headers: Option<&str>,
+    ) -> Vec<u8> {
+        let path = url.path();
+        let path_and_query = match url.query() {
+            Some(q) => format!("{}?{}", path, q),
+            None => path.to_string(),
+        };
+
+        let mut request = format!("{} {} {}\r\n", method, path_and_query, version);
+        for line in headers_lines {
+            if line.trim().is_empty()
+                && let Some(conditional_headers) = conditional_headers
+            {
+                request.push_str(conditional_headers);
+            }
+            if !line.to_lowercase().starts_with("proxy-") {
+                request.push_str(line);
+            }
+        }
+        request.into_bytes()
+    }
+
+    // The client already got its stale copy at this point, so waiting in the
+    // throttler here holds nobody up
+    async fn refresh_in_background(
+        &self,
+        target_addr: &str,
+        cache_key: &str,
+        upstream_request: &[u8],
+        revalidating: bool,
+        request: (&str, &Url),
+    ) {
+        if self.refreshing.insert(cache_key.to_string(), ()).is_some() {
+            return; //another connection is already refreshing this entry
+        }
+
+        if let Err(e) = self
+            .fetch_into_cache(
+                target_addr,
+                cache_key,
+                upstream_request,
+                revalidating,
+                true,
+                request,
+            )
+            .await
+        {
+            eprintln!("Background refresh of {} failed: {}", target_addr, e);
+        }
+
+        self.refreshing.remove(cache_key);
+    }
+
+    async fn fetch_into_cache(
+        &self,
+        target_addr: &str,
+        cache_key: &str,
+        upstream_request: &[u8],
+        revalidating: bool,
+        store_negative: bool,
+        (method, url): (&str, &Url),
+    ) -> Result<u16, Box<dyn std::error::Error + Send + Sync>> {
+        if self.offline_mode() != OfflineMode::Online {
+            return Err("Offline, not fetching".into());
+        }
+        self.throttler.throttle(target_addr).await;
+
+        let mut target_stream = Self::connect_upstream(target_addr)
+            .await
+            .map_err(|(e, _)| e)?;
+        target_stream.write_all(upstream_request).await?;
+
+        let mut head_buffer = Vec::new();
+        let Some(head_len) = http::read_head(&mut target_stream, &mut head_buffer).await? else {
+            return Err("Upstream closed before sending a response".into());
+        };
+        let head = http::ResponseHead::parse(&head_buffer).ok_or("Invalid response head")?;
+
+        match head.status {
+            304 if revalidating => {
+                self.cache.refresh(cache_key, &head.headers).await?;
+            }
+            status
+                if (status < 400 && status != 304)
+                    || (matches!(status, 404 | 410)
+                        && store_negative
+                        && self.cache.caches_failures()) =>
+            {
+                let metadata = Self::request_metadata(head.to_metadata(), method, url);
+                let mut cache_writer = self.cache.open_write(cache_key, metadata).await?;
+                cache_writer.write_all(&head_buffer[head_len..]).await?;
+                tokio::io::copy(&mut target_stream, &mut cache_writer).await?;
+                cache_writer.shutdown().await?;
+            }
+            _ => {} //keep the stale copy around, also over a 429 or a 403
+        }
+        Ok(head.status)
+    }
+
+    // Streams a stored entry to the client, optionally tagged with a `Warning`
+    async fn serve_cached<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        cache_key: &str,
+        warning: Option<&str>,
+        waited: Duration,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let (mut metadata, mut reader) = self
+            .cache
+            .open_read(cache_key)
+            .await?
+            .ok_or("Cached entry vanished")?;
+
+        http::set_age(&mut metadata, Self::now());
+        let mut head = http::response_head(&metadata);
+        if let Some(warning) = warning {
+            head = http::insert_header(&head, "Warning", warning);
+        }
+        //Stale entries are always served with a warning
+        let x_cache = if warning.is_some() { "STALE" } else { "HIT" };
+        let head = http::insert_cache_headers(&head, x_cache, waited);
+
+        stream.write_all(&head).await?;
+        tokio::io::copy(&mut reader, stream).await?;
+        stream.flush().await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    // A failing cache write only costs the cache entry, never the client response
+    async fn tee(cache_writer: &mut Option<CacheWriter<'_, T>>, bytes: &[u8]) {
+        if let Some(writer) = cache_writer
+            && writer.write_all(bytes).await.is_err()
+        {
+            *cache_writer = None;
+        }
+    }
+
+    async fn commit(cache_writer: Option<CacheWriter<'_, T>>) {
+        if let Some(mut writer) = cache_writer {
+            writer.shutdown().await.ok();
+        }
+    }
+}
+
+#[async_trait]
+impl<T: CacheStorage + Send + Sync + 'static, U: Throttle + Send + Sync + 'static> Limiter
+    for Server<T, U>
+{
+    async fn run(self: Arc<Self>) {
+        info!("Starting the server...");
+        let listener = TcpListener::bind(format!("{}:{}", self.ip, self.port))
+            .await
+            .expect("Failed to bind to address");
+
+        loop {
+            let (client_stream, addr) = listener
+                .accept()
+                .await
+                .expect("Failed to accept connection");
+
+            let server_clone = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = server_clone.handle_connection(client_stream).await {
+                    eprintln!("Error handling connection from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_proxy_server_caches_requests() {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+
+        let hit_counter = Arc::new(AtomicUsize::new(0));
+        let hit_counter_clone = hit_counter.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Ok((mut socket, _)) = upstream_listener.accept().await {
+                    let counter = hit_counter_clone.clone();
+                    tokio::spawn(async move {
+                        counter.fetch_add(1, Ordering::SeqCst);
+
+                        let mut buf = [0u8; 1024];
+                        let _ = socket.read(&mut buf).await;
+
+                        let response = "HTTP/1.1 200 OK\r\nContent-Length: 12\r\nConnection: close\r\n\r\nHello World!";
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                        socket.flush().await.unwrap();
+                    });
+                }
+            }
+        });
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+
+        let proxy = spawn_proxy(server).await;
+
+        let client = proxy_client(proxy.port);
 
         let target_url = format!("http://{}/resource", upstream_addr);
 
@@ -345,7 +1312,941 @@ mod tests {
             1,
             "Upstream should NOT be hit again (Cache Hit)"
         );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_revalidates_stale_entries() {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+
+        let not_modified_counter = Arc::new(AtomicUsize::new(0));
+        let not_modified_counter_clone = not_modified_counter.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Ok((mut socket, _)) = upstream_listener.accept().await {
+                    let counter = not_modified_counter_clone.clone();
+                    tokio::spawn(async move {
+                        let mut buf = [0u8; 1024];
+                        let n = socket.read(&mut buf).await.unwrap_or(0);
+                        let request = String::from_utf8_lossy(&buf[..n]).to_string();
+
+                        let response = if request.contains("If-None-Match: \"v1\"") {
+                            counter.fetch_add(1, Ordering::SeqCst);
+                            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+                        } else {
+                            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 12\r\nConnection: close\r\n\r\nHello World!"
+                        };
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                        socket.flush().await.unwrap();
+                    });
+                }
+            }
+        });
+
+        // TTL of zero makes every stored entry stale right away
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &0, 10);
+
+        let proxy = spawn_proxy(server).await;
+
+        let client = proxy_client(proxy.port);
+
+        let target_url = format!("http://{}/resource", upstream_addr);
+
+        for _ in 0..2 {
+            let res = client
+                .get(&target_url)
+                .send()
+                .await
+                .expect("Request failed");
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.text().await.unwrap(), "Hello World!");
+        }
+
+        assert_eq!(
+            not_modified_counter.load(Ordering::SeqCst),
+            1,
+            "Second request should be answered by a 304 revalidation"
+        );
+    }
+
+    // Upstream answering each request with `respond(request_number, request_text)`
+    async fn spawn_upstream<F>(respond: F) -> std::net::SocketAddr
+    where
+        F: Fn(usize, &str) -> String + Send + Sync + 'static,
+    {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+        let respond = Arc::new(respond);
+        let request_counter = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(async move {
+            loop {
+                if let Ok((mut socket, _)) = upstream_listener.accept().await {
+                    let respond = respond.clone();
+                    let request_number = request_counter.fetch_add(1, Ordering::SeqCst);
+                    tokio::spawn(async move {
+                        let mut buf = [0u8; 1024];
+                        let n = socket.read(&mut buf).await.unwrap_or(0);
+                        let request = String::from_utf8_lossy(&buf[..n]).to_string();
+
+                        let response = respond(request_number, &request);
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                        socket.flush().await.unwrap();
+                    });
+                }
+            }
+        });
+
+        upstream_addr
+    }
+
+    // A server accepting connections like `Limiter::run`, on a free port
+    struct Proxy {
+        port: u16,
+        handlers: tokio::sync::watch::Receiver<usize>,
+        accept_task: tokio::task::JoinHandle<()>,
+    }
+
+    impl Proxy {
+        // Resolves once no connection is being handled anymore, so entries
+        // are committed and background refreshes done. Created before a
+        // request, it waits for that request's connection too.
+        fn settled(&self) -> impl Future<Output = ()> + use<> {
+            let mut handlers = self.handlers.clone();
+            async move {
+                handlers.wait_for(|running| *running == 0).await.unwrap();
+            }
+        }
+    }
+
+    impl Drop for Proxy {
+        fn drop(&mut self) {
+            self.accept_task.abort();
+        }
+    }
+
+    async fn spawn_proxy<T, U>(server: Arc<Server<T, U>>) -> Proxy
+    where
+        T: CacheStorage + Send + Sync + 'static,
+        U: Throttle + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (running, handlers) = tokio::sync::watch::channel(0usize);
+        let running = Arc::new(running);
+
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let (client_stream, _) = listener.accept().await.unwrap();
+                running.send_modify(|running| *running += 1);
+                let (server, running) = (server.clone(), running.clone());
+                tokio::spawn(async move {
+                    server.handle_connection(client_stream).await.ok();
+                    running.send_modify(|running| *running -= 1);
+                });
+            }
+        });
+        Proxy {
+            port,
+            handlers,
+            accept_task,
+        }
+    }
+
+    fn proxy_client(proxy_port: u16) -> reqwest::Client {
+        let proxy_url = format!("http://127.0.0.1:{}", proxy_port);
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(&proxy_url).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn ok_response(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_serves_stale_while_revalidating() {
+        let upstream_addr =
+            spawn_upstream(|request_number, _| ok_response(&format!("version {}", request_number)))
+                .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &0, 10);
+        server.cache().set_stale_while_revalidate(&60).await;
+
+        let proxy = spawn_proxy(server).await;
+
+        let client = proxy_client(proxy.port);
+        let target_url = format!("http://{}/resource", upstream_addr);
 
-        server_handle.abort();
+        let res1 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res1.headers().get("warning"), None);
+        assert_eq!(res1.text().await.unwrap(), "version 0");
+
+        let res2 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(
+            res2.headers().get("warning").unwrap(),
+            "110 - \"Response is Stale\""
+        );
+        assert_eq!(res2.text().await.unwrap(), "version 0");
+        proxy.settled().await; //background refresh done
+
+        let res3 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(
+            res3.text().await.unwrap(),
+            "version 1",
+            "Background refresh should have replaced the stale entry"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_keeps_stale_copy_on_client_errors() {
+        let upstream_addr = spawn_upstream(|request_number, _| match request_number {
+            0 => ok_response("version 0"),
+            _ => "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        })
+        .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &0, 10);
+        server.cache().set_stale_while_revalidate(&60).await;
+
+        let proxy = spawn_proxy(server).await;
+
+        let client = proxy_client(proxy.port);
+        let target_url = format!("http://{}/resource", upstream_addr);
+
+        let res1 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res1.text().await.unwrap(), "version 0");
+        let res2 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res2.text().await.unwrap(), "version 0");
+        proxy.settled().await; //background refresh done
+
+        let res3 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res3.status(), 200);
+        assert_eq!(
+            res3.text().await.unwrap(),
+            "version 0",
+            "A 429 during the background refresh should not replace the stale entry"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_serves_stale_if_error() {
+        let upstream_addr = spawn_upstream(|request_number, _| match request_number {
+            0 => ok_response("Hello World!"),
+            _ => {
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            }
+        })
+        .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &0, 10);
+        server.cache().set_stale_if_error(&60).await;
+
+        let proxy = spawn_proxy(server).await;
+
+        let client = proxy_client(proxy.port);
+        let target_url = format!("http://{}/resource", upstream_addr);
+
+        let res1 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res1.text().await.unwrap(), "Hello World!");
+
+        let res2 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res2.status(), 200);
+        assert_eq!(
+            res2.headers().get("warning").unwrap(),
+            "111 - \"Revalidation Failed\""
+        );
+        assert_eq!(res2.text().await.unwrap(), "Hello World!");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_survives_corrupted_entries() {
+        let upstream_addr =
+            spawn_upstream(|request_number, _| ok_response(&format!("version {}", request_number)))
+                .await;
+
+        let path = "/tmp/test_limiter_corrupted";
+        std::fs::remove_dir_all(path).ok();
+        let cache = Cache::new_file_cache(&1024, &60, path);
+        let server = Server::new("127.0.0.1", 0, cache, InMemoryThrottler::new(10));
+
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let target_url = format!("http://{}/resource", upstream_addr);
+
+        let res1 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res1.text().await.unwrap(), "version 0");
+        proxy.settled().await; //entry committed
+
+        fn corrupt(dir: &std::path::Path) {
+            for entry in std::fs::read_dir(dir).unwrap().flatten() {
+                if entry.path().is_dir() {
+                    corrupt(&entry.path());
+                } else {
+                    std::fs::write(entry.path(), b"garbage").unwrap();
+                }
+            }
+        }
+        corrupt(std::path::Path::new(path));
+
+        let res2 = client.get(&target_url).send().await.unwrap();
+        assert_eq!(res2.text().await.unwrap(), "version 1");
+        assert_eq!(server.cache_errors(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_coalesces_concurrent_misses() {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream_listener.local_addr().unwrap();
+
+        let hit_counter = Arc::new(AtomicUsize::new(0));
+        let hit_counter_clone = hit_counter.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Ok((mut socket, _)) = upstream_listener.accept().await {
+                    let counter = hit_counter_clone.clone();
+                    tokio::spawn(async move {
+                        counter.fetch_add(1, Ordering::SeqCst);
+
+                        let mut buf = [0u8; 1024];
+                        let _ = socket.read(&mut buf).await;
+
+                        //Slow upstream, so all clients miss while the first fetch runs
+                        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+                        socket
+                            .write_all(ok_response("Hello World!").as_bytes())
+                            .await
+                            .unwrap();
+                        socket.flush().await.unwrap();
+                    });
+                }
+            }
+        });
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+
+        let proxy = spawn_proxy(server).await;
+
+        let client = proxy_client(proxy.port);
+        let target_url = format!("http://{}/resource", upstream_addr);
+
+        let requests = (0..5).map(|_| {
+            let client = client.clone();
+            let target_url = target_url.clone();
+            tokio::spawn(async move {
+                let res = client.get(&target_url).send().await.unwrap();
+                res.text().await.unwrap()
+            })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            assert_eq!(request.await.unwrap(), "Hello World!");
+        }
+
+        assert_eq!(
+            hit_counter.load(Ordering::SeqCst),
+            1,
+            "Concurrent misses should share one upstream fetch"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_invalidation() {
+        let upstream_addr = spawn_upstream(|request_number, _| {
+            let body = format!("version {}", request_number);
+            format!(
+                "HTTP/1.1 200 OK\r\nCache-Tag: release-1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        })
+        .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let fetch = |path: &str| {
+            let request = client.get(format!("http://{}/{}", upstream_addr, path));
+            let settled = proxy.settled();
+            async move {
+                let body = request.send().await.unwrap().text().await.unwrap();
+                settled.await; //entry committed
+                body
+            }
+        };
+
+        assert_eq!(fetch("a").await, "version 0");
+        assert_eq!(fetch("b").await, "version 1");
+        assert_eq!(fetch("a").await, "version 0");
+
+        let url = format!("http://{}/a", upstream_addr);
+        assert_eq!(server.invalidate_url("GET", &url).await.unwrap(), 1);
+        assert_eq!(fetch("a").await, "version 2");
+        assert_eq!(fetch("b").await, "version 1");
+
+        assert_eq!(server.invalidate_tag("release-1").await.unwrap(), 2);
+        assert_eq!(fetch("b").await, "version 3");
+
+        let host = upstream_addr.ip().to_string();
+        assert_eq!(server.invalidate_host(&host).await.unwrap(), 1);
+        assert_eq!(fetch("b").await, "version 4");
+
+        let stats = server.stats();
+        let upstream = &stats.hosts[&upstream_addr.to_string()];
+        assert_eq!((upstream.hits, upstream.misses), (2, 5));
+        assert_eq!(upstream.entries, 1);
+        assert_eq!(stats.total.bytes, "version 4".len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_imports_keyless_archives() {
+        let closed_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let target_url = format!("http://{}/imported", closed_addr);
+        let mut entry = CacheEntry::new_http(200, "OK", Vec::new(), b"from the archive".to_vec());
+        entry.metadata.method = Some("GET".to_string());
+        entry.metadata.url = Some(target_url.clone());
+        let path = "/tmp/test_limiter_import.har";
+        cache::archive::write(
+            path,
+            ArchiveFormat::Har,
+            &[cache::archive::ArchivedEntry { key: None, entry }],
+        )
+        .await
+        .unwrap();
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.set_key_builder(CacheKeyBuilder::new(KeyRules::default().headers(&[])));
+        let report = server.import(path, ArchiveFormat::Har).await.unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: 1,
+                skipped: 0
+            }
+        );
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let res = proxy_client(proxy.port)
+            .get(&target_url)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.text().await.unwrap(), "from the archive");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_caches_failures() {
+        let upstream_addr = spawn_upstream(|request_number, _| {
+            let body = format!("missing {}", request_number);
+            format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        })
+        .await;
+        let closed_addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.cache().set_negative_ttl(&2).await; //whole seconds, so 1 can lapse right away
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let fetch = |url: String| {
+            let request = client.get(url);
+            let settled = proxy.settled();
+            async move {
+                let res = request.send().await.unwrap();
+                let status = res.status().as_u16();
+                let body = res.text().await.unwrap();
+                settled.await; //entry committed
+                (status, body)
+            }
+        };
+
+        let missing = format!("http://{}/missing", upstream_addr);
+        assert_eq!(fetch(missing.clone()).await, (404, "missing 0".to_string()));
+        assert_eq!(fetch(missing.clone()).await, (404, "missing 0".to_string()));
+
+        let refused = format!("http://{}/", closed_addr);
+        let (status, _) = fetch(refused.clone()).await;
+        assert_eq!(status, 502);
+        let (status, _) = fetch(refused.clone()).await;
+        assert_eq!(status, 502);
+        assert_eq!(server.stats().hosts[&closed_addr.to_string()].hits, 1);
+
+        //Failures expire after the negative TTL, not the regular one
+        tokio::time::sleep(tokio::time::Duration::from_millis(2100)).await;
+        assert_eq!(fetch(missing).await, (404, "missing 1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_prefetch() {
+        let upstream_addr = spawn_upstream(|request_number, request| {
+            if request.starts_with("GET /broken") {
+                return "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string();
+            }
+            if request.starts_with("GET /forbidden") {
+                return "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_string();
+            }
+            ok_response(&format!("version {}", request_number))
+        })
+        .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.set_key_builder(CacheKeyBuilder::new(
+            KeyRules::default().headers(&["Accept"]),
+        ));
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let list_path = "/tmp/test_limiter_prefetch.txt";
+        let list = format!(
+            "# warm-up list\nhttp://{0}/a\n\n{{\"url\": \"http://{0}/b\", \"headers\": [[\"Accept\", \"*/*\"]]}}\nhttp://{0}/a\nhttp://{0}/broken\nnot a url\nhttp://{0}/forbidden\n",
+            upstream_addr
+        );
+        std::fs::write(list_path, list).unwrap();
+
+        let report = server.prefetch_file(list_path).await.unwrap();
+        assert_eq!((report.fetched, report.already_cached), (2, 1));
+        let failed_lines: Vec<usize> = report.failed.iter().map(|f| f.line).collect();
+        assert_eq!(failed_lines, vec![6, 7, 8]);
+        assert_eq!(server.stats().total.entries, 2); //the 403 is not cached
+
+        //A client sending the headers the key uses as listed is served from the cache
+        let mut client = TcpStream::connect(("127.0.0.1", proxy.port)).await.unwrap();
+        let request = format!(
+            "GET http://{0}/a HTTP/1.1\r\nHost: {0}\r\nUser-Agent: job\r\n\r\n",
+            upstream_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("version 0"), "{}", response);
+        assert_eq!(server.stats().total.hits, 2);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_normalizes_cache_keys() {
+        let upstream_addr =
+            spawn_upstream(|request_number, _| ok_response(&format!("version {}", request_number)))
+                .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.set_key_builder(CacheKeyBuilder::new(
+            KeyRules::default()
+                .headers(&["Accept"])
+                .drop_params(&["utm_*"]),
+        ));
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let fetch = |query: &str, user_agent: &str, accept: &str| {
+            let request = client
+                .get(format!("http://{}/a{}", upstream_addr, query))
+                .header("User-Agent", user_agent)
+                .header("Accept", accept);
+            let settled = proxy.settled();
+            async move {
+                let body = request.send().await.unwrap().text().await.unwrap();
+                settled.await; //entry committed
+                body
+            }
+        };
+
+        assert_eq!(fetch("?x=1", "job-a", "text/html").await, "version 0");
+        assert_eq!(
+            fetch("?x=1&utm_source=mail", "job-b", "text/html").await,
+            "version 0"
+        );
+        assert_eq!(fetch("?x=1", "job-a", "*/*").await, "version 1");
+        assert_eq!(fetch("?x=2", "job-a", "text/html").await, "version 2");
+    }
+
+    #[tokio::test]
+    async fn test_recording_keeps_the_body_cap() {
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.records_everything.store(true, Ordering::Relaxed);
+        let url = Url::parse("http://example.com/upload").unwrap();
+        let (mut client, mut proxy) = tokio::io::duplex(64);
+
+        let mut headers_lines = vec!["Content-Length: 4\r\n".to_string(), "\r\n".to_string()];
+        client.write_all(b"body").await.unwrap();
+        let body = server
+            .read_keyed_body("PUT", &url, &mut headers_lines, &mut proxy)
+            .await
+            .unwrap();
+        assert_eq!(body, Some(b"body".to_vec()));
+
+        //Left to stream through unrecorded instead of being buffered
+        let mut headers_lines = vec![
+            "Content-Length: 99999999999\r\n".to_string(),
+            "\r\n".to_string(),
+        ];
+        let body = server
+            .read_keyed_body("PUT", &url, &mut headers_lines, &mut proxy)
+            .await
+            .unwrap();
+        assert_eq!(body, None);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_caches_opted_in_request_bodies() {
+        let upstream_addr = spawn_upstream(|request_number, request| {
+            let body = request.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+            ok_response(&format!("{} {}", request_number, body))
+        })
+        .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.set_key_builder(CacheKeyBuilder::default().with_path(
+            &upstream_addr.ip().to_string(),
+            "/graphql",
+            KeyRules::default().cache_bodies(&["POST"]),
+        ));
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let post = |path: &str, body: &'static str| {
+            let request = client
+                .post(format!("http://{}/{}", upstream_addr, path))
+                .body(body);
+            let settled = proxy.settled();
+            async move {
+                let body = request.send().await.unwrap().text().await.unwrap();
+                settled.await; //entry committed
+                body
+            }
+        };
+
+        assert_eq!(post("graphql", "{\"query\": 1}").await, "0 {\"query\": 1}");
+        assert_eq!(post("graphql", "{\"query\": 1}").await, "0 {\"query\": 1}");
+        assert_eq!(post("graphql", "{\"query\": 2}").await, "1 {\"query\": 2}");
+
+        //Not opted in, so every request reaches the upstream
+        assert_eq!(post("users", "{\"id\": 1}").await, "2 {\"id\": 1}");
+        assert_eq!(post("users", "{\"id\": 1}").await, "3 {\"id\": 1}");
+        let delete = || {
+            client
+                .delete(format!("http://{}/users", upstream_addr))
+                .send()
+        };
+        assert_eq!(delete().await.unwrap().text().await.unwrap(), "4 ");
+        assert_eq!(delete().await.unwrap().text().await.unwrap(), "5 ");
+        assert_eq!(server.stats().total.entries, 2);
+
+        //The body is only sent once the proxy asks for it
+        let mut raw = TcpStream::connect(("127.0.0.1", proxy.port)).await.unwrap();
+        let body = "{\"query\": 3}";
+        let head = format!(
+            "POST http://{0}/graphql HTTP/1.1\r\nHost: {0}\r\nContent-Length: {1}\r\nExpect: 100-continue\r\n\r\n",
+            upstream_addr,
+            body.len()
+        );
+        raw.write_all(head.as_bytes()).await.unwrap();
+        let mut interim = [0u8; 25];
+        raw.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        raw.write_all(body.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"6 {\"query\": 3}") {
+            let mut chunk = [0u8; 1024];
+            let read = raw.read(&mut chunk).await.unwrap();
+            assert!(read > 0, "{}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_serves_ranges() {
+        const OBJECT: &str = "0123456789";
+        let upstream_addr = spawn_upstream(|_, request| {
+            let request = request.to_lowercase();
+            let range = request
+                .lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.split_once('-'));
+            match range {
+                Some((first, last)) => {
+                    let (first, last): (usize, usize) =
+                        (first.parse().unwrap(), last.parse().unwrap());
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nETag: \"v1\"\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n{}",
+                        first,
+                        last,
+                        OBJECT.len(),
+                        last - first + 1,
+                        &OBJECT[first..=last]
+                    )
+                }
+                None => ok_response(OBJECT),
+            }
+        })
+        .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 10);
+        server.set_assemble_ranges(true);
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let fetch = |path: &str, range: &str| {
+            let mut request = client.get(format!("http://{}/{}", upstream_addr, path));
+            if !range.is_empty() {
+                request = request.header("Range", range);
+            }
+            let settled = proxy.settled();
+            async move {
+                let response = request.send().await.unwrap();
+                let status = response.status().as_u16();
+                let content_range = response
+                    .headers()
+                    .get("Content-Range")
+                    .map(|value| value.to_str().unwrap().to_string());
+                let body = response.text().await.unwrap();
+                settled.await; //entry committed
+                (status, content_range, body)
+            }
+        };
+        let range = |first: u64, last: u64, body: &str| {
+            (
+                206,
+                Some(format!("bytes {}-{}/10", first, last)),
+                body.to_string(),
+            )
+        };
+
+        //A cached full response answers any range
+        assert_eq!(fetch("full", "").await, (200, None, OBJECT.to_string()));
+        assert_eq!(fetch("full", "bytes=2-4").await, range(2, 4, "234"));
+        assert_eq!(fetch("full", "bytes=-3").await, range(7, 9, "789"));
+        assert_eq!(
+            fetch("full", "bytes=20-").await,
+            (416, Some("bytes */10".to_string()), String::new())
+        );
+        assert_eq!(server.stats().total.hits, 3);
+
+        //Downloading in order caches the whole object
+        assert_eq!(fetch("parts", "bytes=0-5").await, range(0, 5, "012345"));
+        assert_eq!(fetch("parts", "bytes=6-9").await, range(6, 9, "6789"));
+        assert_eq!(fetch("parts", "").await, (200, None, OBJECT.to_string()));
+        assert_eq!(fetch("parts", "bytes=3-3").await, range(3, 3, "3"));
+        assert_eq!(server.stats().total.hits, 5);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_reports_cache_status() {
+        let upstream_addr =
+            spawn_upstream(|request_number, _| ok_response(&format!("version {}", request_number)))
+                .await;
+
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &60, 300);
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let fetch = |path: &str| {
+            let request = client.get(format!("http://{}/{}", upstream_addr, path));
+            let settled = proxy.settled();
+            async move {
+                let response = request.send().await.unwrap();
+                let header = |name: &str| {
+                    response
+                        .headers()
+                        .get(name)
+                        .map(|value| value.to_str().unwrap().to_string())
+                };
+                let headers = (
+                    header("X-Cache"),
+                    header("X-Limiter-Wait-Ms"),
+                    header("Age"),
+                );
+                assert_eq!(
+                    header("Via"),
+                    Some(concat!("1.1 ", env!("CARGO_PKG_NAME")).to_string())
+                );
+                settled.await; //entry committed
+                headers
+            }
+        };
+
+        let (x_cache, _, age) = fetch("a").await;
+        assert_eq!((x_cache.as_deref(), age), (Some("MISS"), None));
+
+        //The second request to the host waits out the rest of the throttle interval
+        let (x_cache, wait_ms, _) = fetch("b").await;
+        assert_eq!(x_cache.as_deref(), Some("MISS"));
+        assert!(wait_ms.unwrap().parse::<u64>().unwrap() >= 100);
+
+        //Stored a moment ago, so at most a second old
+        let (x_cache, wait_ms, age) = fetch("a").await;
+        assert_eq!(
+            (x_cache.as_deref(), wait_ms.as_deref()),
+            (Some("HIT"), Some("0"))
+        );
+        assert!(age.unwrap().parse::<u64>().unwrap() <= 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_offline_mode() {
+        let upstream_addr =
+            spawn_upstream(|request_number, _| ok_response(&format!("version {}", request_number)))
+                .await;
+
+        //Without a TTL every entry is stale right away
+        let server = Server::new_in_memory("127.0.0.1", 0, &1024, &0, 10);
+        let proxy = spawn_proxy(server.clone()).await;
+
+        let client = proxy_client(proxy.port);
+        let fetch = |path: &str| {
+            let request = client.get(format!("http://{}/{}", upstream_addr, path));
+            let settled = proxy.settled();
+            async move {
+                let response = request.send().await.unwrap();
+                let status = response.status().as_u16();
+                let offline = response.headers().contains_key(OFFLINE_HEADER);
+                let body = response.text().await.unwrap();
+                settled.await; //entry committed
+                (status, offline, body)
+            }
+        };
+        let offline_miss = (
+            504,
+            true,
+            "Not cached, and the proxy is offline\n".to_string(),
+        );
+
+        assert_eq!(fetch("a").await, (200, false, "version 0".to_string()));
+
+        server.set_offline(OfflineMode::Offline);
+        assert_eq!(fetch("a").await, offline_miss);
+        assert_eq!(fetch("b").await, offline_miss);
+        let report = server
+            .prefetch(&format!("http://{}/b", upstream_addr))
+            .await;
+        assert_eq!(report.failed.len(), 1);
+
+        server.set_offline(OfflineMode::OfflineIgnoringTtl);
+        assert_eq!(fetch("a").await, (200, false, "version 0".to_string()));
+        assert_eq!(fetch("b").await, offline_miss);
+
+        //Nothing reached the upstream while offline
+        server.set_offline(OfflineMode::Online);
+        assert_eq!(fetch("b").await, (200, false, "version 1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_server_records_and_replays_cassettes() {
+        let upstream_addr = spawn_upstream(|request_number, request| {
+            if request.contains("/missing") {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nConnection: close\r\n\r\ngone"
+                    .to_string()
+            } else if request.to_ascii_lowercase().contains("range: bytes=0-1") {
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-1/9\r\nContent-Length: 2\r\nConnection: close\r\n\r\nve"
+                    .to_string()
+            } else if let Some((_, body)) = request.split_once("\r\n\r\n")
+                && !body.is_empty()
+            {
+                ok_response(&format!("posted {}", body))
+            } else if request.to_ascii_lowercase().contains("if-none-match") {
+                "HTTP/1.1 304 Not Modified\r\nETag: \"v\"\r\nConnection: close\r\n\r\n".to_string()
+            } else {
+                let body = format!("version {}", request_number);
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+        })
+        .await;
+        let cassette = "/tmp/test_limiter_cassette.json";
+
+        let fetch = |proxy: &Proxy, path: &str| {
+            let request =
+                proxy_client(proxy.port).get(format!("http://{}/{}", upstream_addr, path));
+            let settled = proxy.settled();
+            async move {
+                let response = request.send().await.unwrap();
+                let status = response.status().as_u16();
+                let body = response.text().await.unwrap();
+                settled.await; //entry committed
+                (status, body)
+            }
+        };
+
+        let recorder = Server::record("127.0.0.1", 0, cassette, 10).await.unwrap();
+        let proxy = spawn_proxy(recorder).await;
+        assert_eq!(fetch(&proxy, "a").await, (200, "version 0".to_string()));
+        assert_eq!(fetch(&proxy, "b").await, (200, "version 1".to_string()));
+        //Sent as is again rather than revalidated against the first recording
+        assert_eq!(fetch(&proxy, "b").await, (200, "version 2".to_string()));
+        assert_eq!(fetch(&proxy, "missing").await, (404, "gone".to_string()));
+        let uncached = |proxy: &Proxy| {
+            let client = proxy_client(proxy.port);
+            let (posted_settled, ranged_settled) = (proxy.settled(), proxy.settled());
+            async move {
+                let posted = client
+                    .post(format!("http://{}/submit", upstream_addr))
+                    .body("form=1")
+                    .send()
+                    .await
+                    .unwrap();
+                let posted = (posted.status().as_u16(), posted.text().await.unwrap());
+                posted_settled.await; //entry committed
+                let ranged = client
+                    .get(format!("http://{}/a", upstream_addr))
+                    .header("Range", "bytes=0-1")
+                    .send()
+                    .await
+                    .unwrap();
+                let ranged = (ranged.status().as_u16(), ranged.text().await.unwrap());
+                ranged_settled.await; //entry committed
+                (posted, ranged)
+            }
+        };
+        let exchanges = ((200, "posted form=1".to_string()), (206, "ve".to_string()));
+        assert_eq!(uncached(&proxy).await, exchanges);
+        drop(proxy);
+
+        let recorded = std::fs::read_to_string(cassette).unwrap();
+        assert!(recorded.find("version 0").unwrap() < recorded.find("version 2").unwrap());
+        assert!(!recorded.contains("version 1"));
+        assert!(recorded.contains("\"method\": \"POST\""));
+        assert!(recorded.contains("\"request_body\": \"form=1\""));
+        assert!(recorded.contains("\"range\",\n          \"bytes=0-1\""));
+
+        let replayer = Server::replay("127.0.0.1", 0, cassette).await.unwrap();
+        let proxy = spawn_proxy(replayer.clone()).await;
+        assert_eq!(fetch(&proxy, "b").await, (200, "version 2".to_string()));
+        assert_eq!(fetch(&proxy, "a").await, (200, "version 0".to_string()));
+        assert_eq!(fetch(&proxy, "missing").await, (404, "gone".to_string()));
+        assert_eq!(uncached(&proxy).await, exchanges);
+        assert_eq!(fetch(&proxy, "c").await.0, 504);
+        assert_eq!(
+            replayer.offline_misses(),
+            vec![format!("GET http://{}/c", upstream_addr)]
+        );
+        drop(proxy);
+
+        assert!(
+            Server::replay("127.0.0.1", 0, "/tmp/test_limiter_no_cassette.json")
+                .await
+                .is_err()
+        );
     }
 }
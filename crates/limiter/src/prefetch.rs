@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use url::Url;
+
+use cache::CacheLookup;
+use cache::storage::CacheStorage;
+use throttle::Throttle;
+
+use crate::Server;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefetchReport {
+    pub fetched: usize,
+    // Fresh in the cache already, so not fetched again
+    pub already_cached: usize,
+    pub failed: Vec<PrefetchFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefetchFailure {
+    // 1-based line in the list
+    pub line: usize,
+    pub request: String,
+    pub error: String,
+}
+
+// One JSONL line; plain lines are just the URL
+#[derive(Deserialize)]
+struct ListedRequest {
+    url: String,
+    #[serde(default = "default_method")]
+    method: String,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+struct Prefetch {
+    line: usize,
+    method: String,
+    url: Url,
+    target_addr: String,
+    cache_key: String,
+    upstream_request: Vec<u8>,
+}
+
+impl<T, U> Server<T, U>
+where
+    T: CacheStorage + Send + Sync + 'static,
+    U: Throttle + Send + Sync + 'static,
+{
+    pub async fn prefetch_file(self: &Arc<Self>, path: &str) -> std::io::Result<PrefetchReport> {
+        let list = tokio::fs::read_to_string(path).await?;
+        Ok(self.prefetch(&list).await)
+    }
+
+    // Fetches every listed request into the cache ahead of time. Lines are
+    // URLs or JSON objects like `{"url": .., "method": "GET", "headers":
+    // [["Accept", "*/*"]]}`; blank lines and `#` comments are skipped. Keys
+    // follow the server's key rules, so list headers the way the jobs send the
+    // ones those rules use. The default rules key on every header as sent,
+    // which a list cannot reproduce: restrict them with `KeyRules::headers` for
+    // prefetched entries to be hit. Error responses count as failures and are
+    // not cached. Hosts are fetched concurrently, each through the throttler.
+    pub async fn prefetch(self: &Arc<Self>, list: &str) -> PrefetchReport {
+        let mut report = PrefetchReport::default();
+        let mut by_host: HashMap<String, Vec<Prefetch>> = HashMap::new();
+        for (index, line) in list.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match self.parse_prefetch(index + 1, line) {
+                Ok(prefetch) => {
+                    if self.keys_all_headers(&prefetch.url) {
+                        warn!(
+                            "Prefetching {} under key rules that use every client header, \
+                             clients will likely miss it",
+                            prefetch.url
+                        );
+                    }
+                    by_host
+                        .entry(prefetch.target_addr.clone())
+                        .or_default()
+                        .push(prefetch)
+                }
+                Err(error) => report.failed.push(PrefetchFailure {
+                    line: index + 1,
+                    request: line.to_string(),
+                    error,
+                }),
+            }
+        }
+
+        let total: usize = by_host.values().map(Vec::len).sum();
+        let done = Arc::new(AtomicUsize::new(0));
+        let mut tasks = JoinSet::new();
+        for prefetches in by_host.into_values() {
+            let server = self.clone();
+            let done = done.clone();
+            tasks.spawn(async move {
+                let mut report = PrefetchReport::default();
+                for prefetch in prefetches {
+                    let outcome = server.prefetch_one(&prefetch).await;
+                    let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    match outcome {
+                        Ok(true) => {
+                            info!("Prefetched {}/{}: {}", done, total, prefetch.url);
+                            report.fetched += 1;
+                        }
+                        Ok(false) => {
+                            info!("Prefetched {}/{}: {} (cached)", done, total, prefetch.url);
+                            report.already_cached += 1;
+                        }
+                        Err(error) => {
+                            warn!(
+                                "Prefetch {}/{} of {} failed: {}",
+                                done, total, prefetch.url, error
+                            );
+                            report.failed.push(PrefetchFailure {
+                                line: prefetch.line,
+                                request: format!("{} {}", prefetch.method, prefetch.url),
+                                error,
+                            });
+                        }
+                    }
+                }
+                report
+            });
+        }
+
+        while let Some(host_report) = tasks.join_next().await {
+            let Ok(host_report) = host_report else {
+                continue;
+            };
+            report.fetched += host_report.fetched;
+            report.already_cached += host_report.already_cached;
+            report.failed.extend(host_report.failed);
+        }
+        report.failed.sort_by_key(|failure| failure.line);
+        report
+    }
+
+    // Whether the request had to be fetched
+    async fn prefetch_one(&self, prefetch: &Prefetch) -> Result<bool, String> {
+        if let CacheLookup::Fresh(_) = self.lookup(&prefetch.cache_key, Some(&prefetch.url)).await {
+            return Ok(false);
+        }
+
+        let status = self
+            .fetch_into_cache(
+                &prefetch.target_addr,
+                &prefetch.cache_key,
+                &prefetch.upstream_request,
+                false,
+                false,
+                (&prefetch.method, &prefetch.url),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        if status >= 400 {
+            return Err(format!("Upstream answered {}", status));
+        }
+        Ok(true)
+    }
+
+    // Builds the request a proxy client would send, keyed like one
+    fn parse_prefetch(&self, line: usize, listed: &str) -> Result<Prefetch, String> {
+        let listed = if listed.starts_with('{') {
+            serde_json::from_str(listed).map_err(|e| format!("Invalid JSON: {}", e))?
+        } else {
+            ListedRequest {
+                url: listed.to_string(),
+                method: default_method(),
+                headers: Vec::new(),
+            }
+        };
+        //Anything else has a body, which a list cannot carry
+        if !matches!(listed.method.as_str(), "GET" | "HEAD") {
+            return Err(format!("Cannot prefetch {} requests", listed.method));
+        }
+
+        let url = Url::parse(&listed.url).map_err(|e| format!("Invalid URL: {}", e))?;
+        let host = url.host_str().ok_or("URL has no host")?;
+        let target_addr = format!("{}:{}", host, url.port_or_known_default().unwrap_or(80));
+
+        let mut headers = listed.headers;
+        let has_header = |headers: &[(String, String)], name: &str| {
+            headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name))
+        };
+        if !has_header(&headers, "Host") {
+            let authority = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            headers.insert(0, ("Host".to_string(), authority));
+        }
+        let mut headers_lines: Vec<String> = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        headers_lines.push("\r\n".to_string());
+
+        //The body is read until the upstream closes, so never keep it open
+        let connection_close =
+            (!has_header(&headers, "Connection")).then_some("Connection: close\r\n");
+        let upstream_request = Self::build_upstream_request(
+            &listed.method,
+            &url,
+            "HTTP/1.1",
+            &headers_lines,
+            connection_close,
+        );
+
+        Ok(Prefetch {
+            line,
+            cache_key: self.cache_key(&listed.method, &url, &headers_lines, &[]),
+            method: listed.method,
+            url,
+            target_addr,
+            upstream_request,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cache::storage::InMemoryStorage;
+    use throttle::InMemoryThrottler;
+
+    type TestServer = Server<InMemoryStorage, InMemoryThrottler>;
+
+    #[test]
+    fn test_parse_prefetch() {
+        let server = TestServer::new_in_memory("127.0.0.1", 0, &10, &60, 0);
+        let headers =
+            |lines: &[&str]| -> Vec<String> { lines.iter().map(|line| line.to_string()).collect() };
+
+        let plain = server
+            .parse_prefetch(1, "http://example.com/a?b=c")
+            .unwrap();
+        assert_eq!(plain.target_addr, "example.com:80");
+        assert_eq!(
+            plain.cache_key,
+            server.cache_key(
+                "GET",
+                &plain.url,
+                &headers(&["Host: example.com\r\n", "\r\n"]),
+                &[]
+            )
+        );
+        assert_eq!(
+            String::from_utf8(plain.upstream_request).unwrap(),
+            "GET /a?b=c HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+
+        let json = r#"{"url": "http://example.com:8080/", "method": "HEAD", "headers": [["Accept", "*/*"]]}"#;
+        let listed = server.parse_prefetch(2, json).unwrap();
+        assert_eq!(listed.method, "HEAD");
+        assert_eq!(
+            listed.cache_key,
+            server.cache_key(
+                "HEAD",
+                &listed.url,
+                &headers(&["Host: example.com:8080\r\n", "Accept: */*\r\n", "\r\n"]),
+                &[]
+            )
+        );
+
+        assert!(server.parse_prefetch(3, "not a url").is_err());
+        assert!(
+            server
+                .parse_prefetch(4, r#"{"url": "http://a.com/", "method": "POST"}"#)
+                .is_err()
+        );
+        assert!(server.parse_prefetch(5, "{broken").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use cache::{CacheEntry, EntryMetadata};
+
+use crate::http::ResponseHead;
+
+// Objects larger than this are never assembled from ranges
+const MAX_ASSEMBLED_OBJECT: u64 = 64 * 1024 * 1024;
+// Partial objects are dropped once no part arrived for this long, and the
+// least recently extended ones once there are too many or they hold too much
+const PARTIAL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_PARTIAL_OBJECTS: usize = 64;
+const MAX_PARTIAL_BYTES: usize = 256 * 1024 * 1024;
+
+// A single range of a `Range: bytes=` header. Multiple ranges would need a
+// multipart response, so those are not served from the cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ByteRange {
+    From(u64),
+    Between(u64, u64),
+    Suffix(u64),
+}
+
+impl ByteRange {
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let spec = header.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        match (start.is_empty(), end.is_empty()) {
+            (true, false) => Some(ByteRange::Suffix(end.parse().ok()?)),
+            (false, true) => Some(ByteRange::From(start.parse().ok()?)),
+            (false, false) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                (start <= end).then_some(ByteRange::Between(start, end))
+            }
+            (true, true) => None,
+        }
+    }
+
+    // Inclusive first and last byte within a body of `total` bytes, `None`
+    // when the range is unsatisfiable
+    pub fn resolve(&self, total: u64) -> Option<(u64, u64)> {
+        let last = total.checked_sub(1)?;
+        match *self {
+            ByteRange::From(start) => (start <= last).then_some((start, last)),
+            ByteRange::Between(start, end) => (start <= last).then_some((start, end.min(last))),
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(length) => Some((total.saturating_sub(length), last)),
+        }
+    }
+}
+
+// `bytes 0-99/1000` as first byte, last byte and total
+pub(crate) fn content_range(header: &str) -> Option<(u64, u64, u64)> {
+    let (range, total) = header.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end, total) = (
+        start.trim().parse().ok()?,
+        end.trim().parse().ok()?,
+        total.trim().parse().ok()?,
+    );
+    (start <= end && end < total).then_some((start, end, total))
+}
+
+struct PartialObject {
+    etag: Option<String>,
+    metadata: EntryMetadata,
+    body: Vec<u8>,
+    total: u64,
+    updated: Instant,
+}
+
+// Stitches `206` responses fetched in order (0-99, 100-199, ..) back into the
+// full object. A gap, an overlap or a changed ETag starts over.
+#[derive(Default)]
+pub(crate) struct RangeAssembler {
+    partial: DashMap<String, PartialObject>,
+}
+
+impl RangeAssembler {
+    // The full `200` entry once the last part of `cache_key` arrived
+    pub fn add(&self, cache_key: &str, head: &ResponseHead, body: &[u8]) -> Option<CacheEntry> {
+        let Some((start, end, total)) = head.header("Content-Range").and_then(content_range) else {
+            self.partial.remove(cache_key);
+            return None;
+        };
+        if head.status != 206
+            || body.len() as u64 != end - start + 1
+            || total > MAX_ASSEMBLED_OBJECT
+        {
+            self.partial.remove(cache_key);
+            return None;
+        }
+        let etag = head.header("ETag").map(str::to_string);
+
+        if start == 0 {
+            self.partial.insert(
+                cache_key.to_string(),
+                PartialObject {
+                    etag,
+                    metadata: Self::full_metadata(head, total),
+                    body: body.to_vec(),
+                    total,
+                    updated: Instant::now(),
+                },
+            );
+        } else {
+            let mut partial = self.partial.get_mut(cache_key)?;
+            if partial.body.len() as u64 != start || partial.total != total || partial.etag != etag
+            {
+                drop(partial);
+                self.partial.remove(cache_key);
+                return None;
+            }
+            partial.body.extend_from_slice(body);
+            partial.updated = Instant::now();
+        }
+        self.evict(cache_key);
+
+        let (_, partial) = self.partial.remove_if(cache_key, |_, partial| {
+            partial.body.len() as u64 == partial.total
+        })?;
+        Some(CacheEntry {
+            metadata: partial.metadata,
+            body: partial.body,
+        })
+    }
+
+    fn evict(&self, keep: &str) {
+        let now = Instant::now();
+        self.partial
+            .retain(|_, partial| now.duration_since(partial.updated) < PARTIAL_IDLE_TIMEOUT);
+
+        loop {
+            let mut bytes = 0;
+            let mut oldest: Option<(Instant, String)> = None;
+            for partial in self.partial.iter() {
+                bytes += partial.body.len();
+                if partial.key() != keep
+                    && oldest
+                        .as_ref()
+                        .is_none_or(|(updated, _)| partial.updated < *updated)
+                {
+                    oldest = Some((partial.updated, partial.key().clone()));
+                }
+            }
+            if self.partial.len() <= MAX_PARTIAL_OBJECTS && bytes <= MAX_PARTIAL_BYTES {
+                return;
+            }
+            let Some((_, key)) = oldest else {
+                return;
+            };
+            self.partial.remove(&key);
+        }
+    }
+
+    fn full_metadata(head: &ResponseHead, total: u64) -> EntryMetadata {
+        let mut metadata = head.to_metadata();
+        metadata.status = Some(200);
+        metadata.reason = "OK".to_string();
+        metadata.headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Range")
+                && !name.eq_ignore_ascii_case("Content-Length")
+        });
+        metadata
+            .headers
+            .push(("Content-Length".to_string(), total.to_string()));
+        metadata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_resolve_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-99"),
+            Some(ByteRange::Between(0, 99))
+        );
+        assert_eq!(ByteRange::parse("bytes=100-"), Some(ByteRange::From(100)));
+        assert_eq!(ByteRange::parse("bytes=-10"), Some(ByteRange::Suffix(10)));
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6"), None);
+        assert_eq!(ByteRange::parse("bytes=9-1"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+
+        assert_eq!(ByteRange::Between(0, 99).resolve(50), Some((0, 49)));
+        assert_eq!(ByteRange::From(10).resolve(50), Some((10, 49)));
+        assert_eq!(ByteRange::From(50).resolve(50), None);
+        assert_eq!(ByteRange::Suffix(10).resolve(50), Some((40, 49)));
+        assert_eq!(ByteRange::Suffix(100).resolve(50), Some((0, 49)));
+        assert_eq!(ByteRange::Suffix(10).resolve(0), None);
+
+        assert_eq!(content_range("bytes 0-99/1000"), Some((0, 99, 1000)));
+        assert_eq!(content_range("bytes */1000"), None);
+    }
+
+    #[test]
+    fn test_assemble_sequential_ranges() {
+        let part = |range: &str, etag: &str| ResponseHead {
+            version: "HTTP/1.1".to_string(),
+            status: 206,
+            reason: "Partial Content".to_string(),
+            headers: vec![
+                ("ETag".to_string(), etag.to_string()),
+                ("Content-Range".to_string(), range.to_string()),
+                ("Content-Length".to_string(), "4".to_string()),
+            ],
+        };
+        let assembler = RangeAssembler::default();
+        let add =
+            |range: &str, etag: &str, body: &[u8]| assembler.add("key", &part(range, etag), body);
+
+        assert!(add("bytes 4-7/10", "a", b"4567").is_none());
+        assert!(add("bytes 0-3/10", "a", b"0123").is_none());
+        assert!(add("bytes 4-7/10", "b", b"4567").is_none());
+
+        assert!(add("bytes 0-3/10", "a", b"0123").is_none());
+        assert!(add("bytes 4-7/10", "a", b"4567").is_none());
+        let entry = add("bytes 8-9/10", "a", b"89").unwrap();
+        assert_eq!(entry.body, b"0123456789".to_vec());
+        assert_eq!(entry.metadata.status, Some(200));
+        assert_eq!(entry.metadata.header("Content-Length"), Some("10"));
+        assert_eq!(entry.metadata.header("Content-Range"), None);
+        assert!(assembler.partial.is_empty());
+    }
+
+    #[test]
+    fn test_partial_objects_are_bounded() {
+        let part = ResponseHead {
+            version: "HTTP/1.1".to_string(),
+            status: 206,
+            reason: "Partial Content".to_string(),
+            headers: vec![("Content-Range".to_string(), "bytes 0-3/10".to_string())],
+        };
+        let assembler = RangeAssembler::default();
+
+        for i in 0..=MAX_PARTIAL_OBJECTS {
+            assert!(
+                assembler
+                    .add(&format!("key{}", i), &part, b"0123")
+                    .is_none()
+            );
+        }
+        assert_eq!(assembler.partial.len(), MAX_PARTIAL_OBJECTS);
+        assert!(!assembler.partial.contains_key("key0")); //least recently extended
+
+        let idle = Instant::now().checked_sub(PARTIAL_IDLE_TIMEOUT).unwrap();
+        assembler.partial.get_mut("key1").unwrap().updated = idle;
+        assert!(assembler.add("fresh", &part, b"0123").is_none());
+        assert!(!assembler.partial.contains_key("key1"));
+        assert!(assembler.partial.contains_key("fresh"));
+    }
+}
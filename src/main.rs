@@ -56,7 +56,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
 async fn throttle_host(host: &str, throttle_duration_ms: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
     let required_delay = Duration::from_millis(throttle_duration_ms);
-    let mut wait_duration = Duration::from_secs(0);
     let now = Instant::now();
 
     let wait_duration = {
@@ -101,7 +100,7 @@ async fn handle_connection(
         return Err("Client closed connection prematurely".into());
     }
 
-    let parts: Vec<&str> = first_line.trim().split_whitespace().collect();
+    let parts: Vec<&str> = first_line.split_whitespace().collect();
     if parts.len() < 3 {
         return Err("Invalid HTTP request line".into());
     }